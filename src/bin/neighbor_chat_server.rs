@@ -0,0 +1,49 @@
+//! A self-hostable chat server compatible with the O4 protocol.
+//!
+//! Usage: `neighbor_chat_server [address] [channel...]`. Listens on `127.0.0.1:10000` with a
+//! `main` channel by default. Typing `quit` shuts the server down and notifies the clients.
+
+use std::env;
+use std::io;
+use std::time::Duration;
+
+use neighbor_chat::chat_server::ChatServer;
+use smol::io::{AsyncBufReadExt, BufReader};
+
+fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let addr = args
+        .next()
+        .unwrap_or_else(|| String::from("127.0.0.1:10000"));
+    let mut channels: Vec<String> = args.collect();
+    if channels.is_empty() {
+        channels.push(String::from("main"));
+    }
+    let channels: Vec<(&str, &str)> = channels.iter().map(|name| (name.as_str(), "")).collect();
+
+    smol::block_on(async {
+        let server = ChatServer::start(&addr, &channels).await?;
+        println!("Listening on {}", server.local_addr());
+
+        let mut stdin = BufReader::new(smol::Unblock::new(io::stdin()));
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if stdin.read_line(&mut line).await? == 0 {
+                // no terminal attached, run until killed
+                smol::future::pending::<()>().await;
+            }
+            match line.trim() {
+                "quit" => break,
+                "clients" => println!("{} clients connected", server.client_count()),
+                "" => {}
+                other => println!("unknown command {other}, try quit or clients"),
+            }
+        }
+
+        server.shutdown("Server is shutting down");
+        // give the connections a moment to flush the goodbyes
+        smol::Timer::after(Duration::from_millis(200)).await;
+        Ok(())
+    })
+}
@@ -0,0 +1,37 @@
+//! Lets WebSocket clients, like the app with a `ws://` address, reach an O4 server over TCP.
+//!
+//! Usage: `neighbor_chat_ws_bridge [listen address] [server address]`. Listens on
+//! `127.0.0.1:10001` and connects every client to `127.0.0.1:10000` by default. Clients then use
+//! `ws://<listen address>/` as the server address.
+
+use std::env;
+use std::io;
+
+use neighbor_chat::websocket::bridge_client;
+use smol::net::TcpListener;
+
+fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let addr = args
+        .next()
+        .unwrap_or_else(|| String::from("127.0.0.1:10001"));
+    let server = args
+        .next()
+        .unwrap_or_else(|| String::from("127.0.0.1:10000"));
+
+    smol::block_on(async {
+        let listener = TcpListener::bind(&addr).await?;
+        println!("Bridging ws://{}/ to {}", listener.local_addr()?, server);
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = server.clone();
+            smol::spawn(async move {
+                if let Err(err) = bridge_client(stream, &server).await {
+                    println!("{peer}: {err}");
+                }
+            })
+            .detach();
+        }
+    })
+}
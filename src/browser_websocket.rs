@@ -0,0 +1,185 @@
+//! The O4 protocol over the browser's WebSocket, for `wasm32` builds.
+//!
+//! Browsers don't give pages sockets, so `ws://` and `wss://` addresses are the only ones a
+//! browser build connects to, and TLS and proxies are left to the browser. Frames are the same as
+//! with the native WebSocket transport: every packet travels in its own text frame, without the
+//! trailing newline. [`BrowserWebSocket`] turns frames into lines and back.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_channel::Receiver;
+use futures_lite::Stream;
+use futures_lite::io::{AsyncRead, AsyncWrite};
+use send_wrapper::SendWrapper;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{BinaryType, Event, MessageEvent, WebSocket};
+
+use crate::transport::Transport;
+
+/// Handlers the browser calls, kept alive as long as the socket is.
+struct Callbacks {
+    on_open: Closure<dyn FnMut(Event)>,
+    on_message: Closure<dyn FnMut(MessageEvent)>,
+    on_close: Closure<dyn FnMut(Event)>,
+}
+
+/// A browser WebSocket read and written as newline delimited packets.
+///
+/// The socket lives on the page's only thread, which is the only one a `wasm32` build has, so
+/// it's wrapped to meet [`Transport`]'s `Send` bound.
+pub struct BrowserWebSocket {
+    ws: SendWrapper<WebSocket>,
+    _callbacks: SendWrapper<Callbacks>,
+    incoming: Pin<Box<Receiver<Vec<u8>>>>,
+    /// What's left of the last frame received, with its newline.
+    unread: Vec<u8>,
+    /// Written bytes that don't make up a whole line yet.
+    unsent: Vec<u8>,
+}
+
+/// Opens a WebSocket to a `ws://` or `wss://` URL and waits for the browser to connect it.
+pub(crate) async fn connect(url: &str) -> io::Result<BrowserWebSocket> {
+    let ws = WebSocket::new(url).map_err(|err| js_error(io::ErrorKind::InvalidInput, err))?;
+    ws.set_binary_type(BinaryType::Arraybuffer);
+
+    let (frames_tx, frames_rx) = async_channel::unbounded();
+    // true once it's open, false if it closed before that
+    let (opened_tx, opened_rx) = async_channel::bounded(1);
+    let callbacks = Callbacks {
+        on_open: Closure::new({
+            let opened_tx = opened_tx.clone();
+            move |_: Event| {
+                let _ = opened_tx.try_send(true);
+            }
+        }),
+        on_message: Closure::new({
+            let frames_tx = frames_tx.clone();
+            move |event: MessageEvent| {
+                let data = event.data();
+                let frame = match data.as_string() {
+                    Some(text) => text.into_bytes(),
+                    None => match data.dyn_ref::<js_sys::ArrayBuffer>() {
+                        Some(buffer) => js_sys::Uint8Array::new(buffer).to_vec(),
+                        None => return,
+                    },
+                };
+                let _ = frames_tx.try_send(frame);
+            }
+        }),
+        // errors are followed by a close, which ends the stream
+        on_close: Closure::new(move |_: Event| {
+            let _ = opened_tx.try_send(false);
+            frames_tx.close();
+        }),
+    };
+    ws.set_onopen(Some(callbacks.on_open.as_ref().unchecked_ref()));
+    ws.set_onmessage(Some(callbacks.on_message.as_ref().unchecked_ref()));
+    ws.set_onclose(Some(callbacks.on_close.as_ref().unchecked_ref()));
+    let transport = BrowserWebSocket {
+        ws: SendWrapper::new(ws),
+        _callbacks: SendWrapper::new(callbacks),
+        incoming: Box::pin(frames_rx),
+        unread: vec![],
+        unsent: vec![],
+    };
+
+    match opened_rx.recv().await {
+        Ok(true) => Ok(transport),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("the browser couldn't open a WebSocket to {url}"),
+        )),
+    }
+}
+
+fn js_error(kind: io::ErrorKind, err: JsValue) -> io::Error {
+    let message = match err.dyn_ref::<js_sys::Error>() {
+        Some(err) => String::from(err.message()),
+        None => format!("{err:?}"),
+    };
+    io::Error::new(kind, message)
+}
+
+impl BrowserWebSocket {
+    /// Sends a frame for every complete line written so far. The browser buffers what it can't
+    /// send right away.
+    fn send_lines(&mut self) -> io::Result<()> {
+        while let Some(end) = self.unsent.iter().position(|b| *b == b'\n') {
+            let mut line: Vec<u8> = self.unsent.drain(..=end).collect();
+            line.pop();
+            let text = String::from_utf8(line)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            self.ws
+                .send_with_str(&text)
+                .map_err(|err| js_error(io::ErrorKind::BrokenPipe, err))?;
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for BrowserWebSocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.unread.is_empty() {
+            match self.incoming.as_mut().poll_next(cx) {
+                Poll::Ready(Some(frame)) => self.unread = frame,
+                // closed by either side
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+            // an empty frame isn't a packet, and would read as the end of the stream
+            if !self.unread.is_empty() {
+                self.unread.push(b'\n');
+            }
+        }
+        let len = buf.len().min(self.unread.len());
+        buf[..len].copy_from_slice(&self.unread[..len]);
+        self.unread.drain(..len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for BrowserWebSocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.unsent.extend_from_slice(buf);
+        Poll::Ready(self.send_lines().map(|_| buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.send_lines())
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.send_lines()?;
+        Poll::Ready(self.shutdown())
+    }
+}
+
+impl Transport for BrowserWebSocket {
+    fn shutdown(&self) -> io::Result<()> {
+        self.incoming.close();
+        self.ws
+            .close()
+            .map_err(|err| js_error(io::ErrorKind::Other, err))
+    }
+}
+
+impl Drop for BrowserWebSocket {
+    fn drop(&mut self) {
+        // the handlers are dropped with the socket, the browser mustn't call them after that
+        self.ws.set_onopen(None);
+        self.ws.set_onmessage(None);
+        self.ws.set_onclose(None);
+        let _ = self.ws.close();
+    }
+}
@@ -0,0 +1,137 @@
+use std::cmp::Reverse;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// A channel on the server, as listed in a `ListChannels` reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelInfo {
+    pub name: String,
+    /// Users on the channel when the list was sent, `None` for a channel only known by name,
+    /// e.g. one we just created.
+    pub user_count: Option<usize>,
+}
+
+impl ChannelInfo {
+    pub fn new(name: String) -> ChannelInfo {
+        ChannelInfo {
+            name,
+            user_count: None,
+        }
+    }
+
+    /// Reads a channel of a `ListChannels` reply, where the user count follows the name, e.g.
+    /// `"the lounge 12"`.
+    pub fn parse(name_with_user_count: &str) -> ChannelInfo {
+        if let Some((name, count)) = name_with_user_count.rsplit_once(' ')
+            && let Ok(count) = count.parse()
+        {
+            return ChannelInfo {
+                name: name.to_string(),
+                user_count: Some(count),
+            };
+        }
+        ChannelInfo::new(name_with_user_count.to_string())
+    }
+}
+
+/// Order of the channels in the sidebar.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelSort {
+    #[default]
+    Name,
+    /// Newest message first, then the most users.
+    Activity,
+}
+
+impl ChannelSort {
+    pub const ALL: [ChannelSort; 2] = [ChannelSort::Name, ChannelSort::Activity];
+}
+
+impl fmt::Display for ChannelSort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelSort::Name => write!(f, "Name"),
+            ChannelSort::Activity => write!(f, "Activity"),
+        }
+    }
+}
+
+/// The channels with `filter` in their name, case insensitively, in `sort` order.
+/// `last_message` gives when the newest message we have of a channel was sent.
+pub fn sort_and_filter(
+    channels: &[ChannelInfo],
+    filter: &str,
+    sort: ChannelSort,
+    last_message: impl Fn(&str) -> Option<i64>,
+) -> Vec<ChannelInfo> {
+    let filter = filter.trim().to_lowercase();
+    let mut channels: Vec<ChannelInfo> = channels
+        .iter()
+        .filter(|channel| channel.name.to_lowercase().contains(&filter))
+        .cloned()
+        .collect();
+    match sort {
+        ChannelSort::Name => channels.sort_by_key(|channel| channel.name.to_lowercase()),
+        ChannelSort::Activity => channels.sort_by_cached_key(|channel| {
+            (
+                Reverse(last_message(&channel.name)),
+                Reverse(channel.user_count),
+                channel.name.to_lowercase(),
+            )
+        }),
+    }
+    channels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(name: &str, user_count: usize) -> ChannelInfo {
+        ChannelInfo {
+            name: name.into(),
+            user_count: Some(user_count),
+        }
+    }
+
+    #[test]
+    fn user_counts_are_read() {
+        assert_eq!(ChannelInfo::parse("main 3"), channel("main", 3));
+        assert_eq!(
+            ChannelInfo::parse("the lounge 12"),
+            channel("the lounge", 12)
+        );
+        assert_eq!(
+            ChannelInfo::parse("lounge"),
+            ChannelInfo::new("lounge".into())
+        );
+    }
+
+    #[test]
+    fn channels_are_sorted_and_filtered() {
+        let channels = vec![
+            channel("main", 5),
+            channel("Dogs", 1),
+            channel("cats", 2),
+            channel("dog show", 9),
+        ];
+        let names = |channels: Vec<ChannelInfo>| -> Vec<String> {
+            channels.into_iter().map(|channel| channel.name).collect()
+        };
+
+        let by_name = sort_and_filter(&channels, "", ChannelSort::Name, |_| None);
+        assert_eq!(names(by_name), ["cats", "dog show", "Dogs", "main"]);
+
+        let last_message = |name: &str| match name {
+            "cats" => Some(200),
+            "Dogs" => Some(100),
+            _ => None,
+        };
+        let by_activity = sort_and_filter(&channels, "", ChannelSort::Activity, last_message);
+        assert_eq!(names(by_activity), ["cats", "Dogs", "dog show", "main"]);
+
+        let filtered = sort_and_filter(&channels, " DOG", ChannelSort::Activity, |_| None);
+        assert_eq!(names(filtered), ["dog show", "Dogs"]);
+    }
+}
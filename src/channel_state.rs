@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use crate::chat_session::SessionState;
+use crate::history::Conversation;
+use crate::packet::ChatMessage;
+
+/// What's remembered about a channel, or a direct message conversation, while another one is
+/// shown.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChannelState {
+    /// Last topic the server sent for the channel, shown until it sends a new one.
+    pub topic: Option<String>,
+    /// Newest message that was on screen.
+    pub last_read: Option<Uuid>,
+    /// Messages of others since the last read one.
+    pub unread: usize,
+    /// Unread messages that mention us, counted in [`ChannelState::unread`] too.
+    pub mentions: usize,
+    /// How far the message history was scrolled, `None` to show the newest messages.
+    pub scroll_offset: Option<f64>,
+    /// Message being written, kept until it's sent.
+    pub draft: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChannelStates {
+    states: HashMap<Conversation, ChannelState>,
+}
+
+impl ChannelStates {
+    pub fn new() -> ChannelStates {
+        ChannelStates::default()
+    }
+
+    pub fn get(&self, conversation: &Conversation) -> Option<&ChannelState> {
+        self.states.get(conversation)
+    }
+
+    pub fn entry(&mut self, conversation: Conversation) -> &mut ChannelState {
+        self.states.entry(conversation).or_default()
+    }
+
+    /// Marks every message `session` has as read, e.g. the history loaded from disk, which would
+    /// all be unread otherwise.
+    pub fn mark_all_read(&mut self, session: &SessionState) {
+        let channels = session
+            .messages
+            .iter()
+            .map(|(channel, messages)| (Conversation::Channel(channel.clone()), messages));
+        let users = session
+            .direct_messages
+            .iter()
+            .map(|(user, messages)| (Conversation::Direct(user.clone()), messages));
+        for (conversation, messages) in channels.chain(users) {
+            let state = self.entry(conversation);
+            state.last_read = messages.last().map(|message| message.id);
+            state.unread = 0;
+            state.mentions = 0;
+        }
+    }
+
+    /// Brings the unread counts of `conversation` up to date with its `messages`. Everything
+    /// counts as read while `reading`, i.e. while the conversation is on screen.
+    pub fn update(
+        &mut self,
+        conversation: &Conversation,
+        messages: &[ChatMessage],
+        nickname: &str,
+        reading: bool,
+    ) {
+        let state = self.entry(conversation.clone());
+        if reading {
+            if let Some(last) = messages.last() {
+                state.last_read = Some(last.id);
+            }
+            state.unread = 0;
+            state.mentions = 0;
+            return;
+        }
+        // a message that's gone, or nothing read yet, leaves everything unread
+        let start = state
+            .last_read
+            .and_then(|id| messages.iter().rposition(|message| message.id == id))
+            .map_or(0, |index| index + 1);
+        let unread = messages[start..]
+            .iter()
+            .filter(|message| message.user != nickname);
+        state.unread = unread.clone().count();
+        state.mentions = unread
+            .filter(|message| mentions(&message.message, nickname))
+            .count();
+    }
+}
+
+/// Whether `text` has `nickname` in it as a whole, case insensitively.
+pub fn mentions(text: &str, nickname: &str) -> bool {
+    let nickname = nickname.trim().to_lowercase();
+    if nickname.is_empty() {
+        return false;
+    }
+    let text = text.to_lowercase();
+    text.match_indices(&nickname).any(|(index, _)| {
+        let before = text[..index].chars().next_back();
+        let after = text[index + nickname.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Packet;
+    use crate::packet_builder::PacketBuilder;
+
+    fn message(user: &str, text: &str) -> ChatMessage {
+        match PacketBuilder::new(user.into()).chat_message(text.into()) {
+            Packet::Chat(message) => message,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn mentions_are_whole_names() {
+        assert!(mentions("hi Alice Smith!", "alice smith"));
+        assert!(mentions("@alice what do you think", "Alice"));
+        assert!(!mentions("malice", "Alice"));
+        assert!(!mentions("alice2", "Alice"));
+        assert!(!mentions("anything", " "));
+    }
+
+    #[test]
+    fn messages_after_the_last_read_are_unread() {
+        let channel = Conversation::Channel("main".into());
+        let mut states = ChannelStates::new();
+        let mut messages = vec![message("bob", "hello")];
+        states.update(&channel, &messages, "alice", true);
+
+        messages.push(message("bob", "alice, are you there?"));
+        messages.push(message("alice", "typed elsewhere"));
+        messages.push(message("carol", "hi"));
+        states.update(&channel, &messages, "alice", false);
+        let state = states.get(&channel).unwrap();
+        assert_eq!((state.unread, state.mentions), (2, 1));
+        assert_eq!(state.last_read, Some(messages[0].id));
+
+        states.update(&channel, &messages, "alice", true);
+        let state = states.get(&channel).unwrap();
+        assert_eq!((state.unread, state.mentions), (0, 0));
+        assert_eq!(state.last_read, Some(messages[3].id));
+    }
+
+    #[test]
+    fn loaded_history_starts_read() {
+        let channel = Conversation::Channel("main".into());
+        let mut session = SessionState::default();
+        session.messages.insert(
+            "main".into(),
+            vec![message("bob", "hello"), message("bob", "alice?")],
+        );
+        let mut states = ChannelStates::new();
+        states.mark_all_read(&session);
+        let messages = &session.messages["main"];
+        assert_eq!(
+            states.get(&channel).unwrap().last_read,
+            Some(messages[1].id)
+        );
+
+        let mut messages = messages.clone();
+        messages.push(message("carol", "hi"));
+        states.update(&channel, &messages, "alice", false);
+        assert_eq!(states.get(&channel).unwrap().unread, 1);
+    }
+
+    #[test]
+    fn a_new_conversation_is_all_unread() {
+        let dm = Conversation::Direct("bob".into());
+        let mut states = ChannelStates::new();
+        states.update(&dm, &[message("bob", "psst")], "alice", false);
+        assert_eq!(states.get(&dm).unwrap().unread, 1);
+    }
+}
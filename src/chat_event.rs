@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use crate::channel_list::ChannelInfo;
+use crate::chat_session::JOIN_CHANNEL_STATUS_REGEX;
+use crate::packet::{ChatMessage, Packet};
+use crate::server_address::ConnectError;
+use crate::tls::UntrustedCertificate;
+
+/// Something that happened in a [`ChatSession`](crate::chat_session::ChatSession), interpreted
+/// from the packets the server sent.
+#[derive(Debug, Clone)]
+pub enum ChatEvent {
+    /// The server moved us to a channel.
+    JoinedChannel(String),
+    TopicChanged(String),
+    /// A chat message, on our channel or, with `directMessageTo` set, sent to us directly.
+    MessageReceived(ChatMessage),
+    /// The channels on the server.
+    ChannelListUpdated(Vec<ChannelInfo>),
+    /// A status message that doesn't have a more specific event.
+    Status(String),
+    /// `fatal` is set when the server is about to close the connection.
+    ServerError {
+        message: String,
+        fatal: bool,
+    },
+    /// The first connection of the session is up.
+    Connected,
+    /// The connection was lost. Reconnect attempts follow.
+    Disconnected,
+    /// Connecting didn't work, and why. An invalid address isn't retried.
+    ConnectFailed(ConnectError),
+    /// Reconnect attempt number `attempt` starts after `delay`.
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    Reconnected,
+    /// The [`ReconnectPolicy`](crate::reconnect_policy::ReconnectPolicy) ran out of attempts.
+    GaveUp,
+    /// The server's TLS certificate has to be trusted before connecting, see
+    /// [`KnownHosts`](crate::tls::KnownHosts).
+    UntrustedCertificate(UntrustedCertificate),
+}
+
+impl ChatEvent {
+    /// Interprets a received packet. Packets that don't mean anything to a client give `None`.
+    pub fn from_packet(packet: Packet) -> Option<ChatEvent> {
+        match packet {
+            Packet::Status { status, .. } => {
+                match JOIN_CHANNEL_STATUS_REGEX.captures(status.as_str()) {
+                    Some(caps) => Some(ChatEvent::JoinedChannel(caps[1].to_string())),
+                    None => Some(ChatEvent::Status(status)),
+                }
+            }
+            Packet::ChangeTopic { topic, .. } => Some(ChatEvent::TopicChanged(topic)),
+            Packet::Chat(message) => Some(ChatEvent::MessageReceived(message)),
+            Packet::ListChannels {
+                channels: Some(channels),
+                ..
+            } => Some(ChatEvent::ChannelListUpdated(
+                channels.iter().map(|chl| ChannelInfo::parse(chl)).collect(),
+            )),
+            Packet::Error {
+                error,
+                clientshutdown,
+                ..
+            } => Some(ChatEvent::ServerError {
+                message: error,
+                fatal: clientshutdown,
+            }),
+            Packet::ListChannels { channels: None, .. }
+            | Packet::JoinChannel { .. }
+            | Packet::Unknown { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::ExtraFields;
+    use crate::packet_builder::PacketBuilder;
+
+    use super::*;
+
+    #[test]
+    fn status_is_interpreted() {
+        let joined = ChatEvent::from_packet(Packet::Status {
+            status: "You joined the channel the lounge".into(),
+            extra: ExtraFields::new(),
+        });
+        assert!(matches!(joined, Some(ChatEvent::JoinedChannel(c)) if c == "the lounge"));
+
+        let other = ChatEvent::from_packet(Packet::Status {
+            status: "Welcome!".into(),
+            extra: ExtraFields::new(),
+        });
+        assert!(matches!(other, Some(ChatEvent::Status(s)) if s == "Welcome!"));
+    }
+
+    #[test]
+    fn channel_list_request_is_not_an_event() {
+        let builder = PacketBuilder::new("test user".into());
+        assert!(ChatEvent::from_packet(builder.list_channels()).is_none());
+
+        let reply = ChatEvent::from_packet(Packet::ListChannels {
+            channels: Some(vec!["main 3".into()]),
+            extra: ExtraFields::new(),
+        });
+        let main = ChannelInfo {
+            name: "main".into(),
+            user_count: Some(3),
+        };
+        assert!(matches!(reply, Some(ChatEvent::ChannelListUpdated(c)) if c == vec![main]));
+    }
+
+    #[test]
+    fn error_fatality_is_kept() {
+        let event = ChatEvent::from_packet(Packet::Error {
+            error: "shutting down".into(),
+            clientshutdown: true,
+            extra: ExtraFields::new(),
+        });
+        assert!(matches!(
+            event,
+            Some(ChatEvent::ServerError { fatal: true, .. })
+        ));
+    }
+}
@@ -0,0 +1,462 @@
+//! A chat server speaking the O4 protocol, built on [`Packet`].
+//!
+//! - new clients are put on the first channel and get a join status and the channel topic
+//! - `JoinChannel` moves the client (creating the channel if needed) and replies the same way
+//! - `ChangeTopic` is broadcast to everyone on the channel
+//! - `ListChannels` is answered with `"<name> <user count>"` entries
+//! - chat messages are forwarded to the other clients on the channel, or with `directMessageTo`
+//!   to the clients using that nickname, on any channel
+//!
+//! Clients don't register a nickname, the server learns it from the `user` field of the messages
+//! they send.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use smol::Task;
+use smol::channel::{Sender, unbounded};
+use smol::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use smol::net::TcpListener;
+
+use crate::packet::{ExtraFields, Packet};
+use crate::transport::SharedTransport;
+
+pub(crate) enum Outgoing {
+    Packet(Packet),
+    /// Only the mock server sends raw lines.
+    #[cfg_attr(not(any(test, feature = "mock-server")), allow(dead_code))]
+    Raw(String),
+    Close,
+}
+
+struct Client {
+    channel: String,
+    nickname: Option<String>,
+    outgoing: Sender<Outgoing>,
+}
+
+/// Channels and connected clients of a server.
+pub(crate) struct ServerState {
+    /// Channel name to topic.
+    channels: BTreeMap<String, String>,
+    default_channel: String,
+    clients: HashMap<usize, Client>,
+    next_client_id: usize,
+}
+
+impl ServerState {
+    pub(crate) fn new(channels: &[(&str, &str)]) -> ServerState {
+        ServerState {
+            channels: channels
+                .iter()
+                .map(|(name, topic)| (name.to_string(), topic.to_string()))
+                .collect(),
+            default_channel: channels
+                .first()
+                .map(|(name, _)| name.to_string())
+                .unwrap_or_else(|| String::from("main")),
+            clients: HashMap::new(),
+            next_client_id: 0,
+        }
+    }
+
+    pub(crate) fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Registers a new client and puts it on the default channel.
+    fn connect(&mut self, outgoing: Sender<Outgoing>) -> usize {
+        let client_id = self.next_client_id;
+        self.next_client_id += 1;
+        self.clients.insert(
+            client_id,
+            Client {
+                channel: self.default_channel.clone(),
+                nickname: None,
+                outgoing,
+            },
+        );
+        self.join(client_id, self.default_channel.clone());
+        client_id
+    }
+
+    fn disconnect(&mut self, client_id: usize) {
+        self.clients.remove(&client_id);
+    }
+
+    pub(crate) fn send_raw(&self, client_id: usize, outgoing: Outgoing) {
+        if let Some(client) = self.clients.get(&client_id) {
+            let _ = client.outgoing.try_send(outgoing);
+        }
+    }
+
+    pub(crate) fn send(&self, client_id: usize, packet: Packet) {
+        self.send_raw(client_id, Outgoing::Packet(packet));
+    }
+
+    /// Sends to every connected client. `make` is called once per client.
+    #[cfg_attr(not(any(test, feature = "mock-server")), allow(dead_code))]
+    pub(crate) fn send_to_all(&self, mut make: impl FnMut() -> Outgoing) {
+        for client in self.clients.values() {
+            let _ = client.outgoing.try_send(make());
+        }
+    }
+
+    pub(crate) fn send_error(&self, client_id: usize, error: &str, clientshutdown: bool) {
+        self.send(
+            client_id,
+            Packet::Error {
+                error: error.to_string(),
+                clientshutdown,
+                extra: ExtraFields::new(),
+            },
+        );
+        if clientshutdown {
+            self.send_raw(client_id, Outgoing::Close);
+        }
+    }
+
+    fn join(&mut self, client_id: usize, channel: String) {
+        let topic = self.channels.entry(channel.clone()).or_default().clone();
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.channel = channel.clone();
+        }
+        self.send(
+            client_id,
+            Packet::Status {
+                status: format!("You joined the channel {channel}"),
+                extra: ExtraFields::new(),
+            },
+        );
+        self.send(
+            client_id,
+            Packet::ChangeTopic {
+                topic,
+                extra: ExtraFields::new(),
+            },
+        );
+    }
+
+    /// Ids of the clients on `channel`.
+    fn members(&self, channel: &str) -> Vec<usize> {
+        self.clients
+            .iter()
+            .filter(|(_, client)| client.channel == channel)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    pub(crate) fn handle(&mut self, client_id: usize, packet: Packet) {
+        let Some(channel) = self.clients.get(&client_id).map(|c| c.channel.clone()) else {
+            return;
+        };
+        match packet {
+            Packet::JoinChannel { channel, .. } => {
+                if channel.trim().is_empty() {
+                    self.send_error(client_id, "Channel name can't be empty", false);
+                } else {
+                    self.join(client_id, channel)
+                }
+            }
+            Packet::ChangeTopic { topic, .. } => {
+                self.channels.insert(channel.clone(), topic.clone());
+                for member in self.members(&channel) {
+                    self.send(
+                        member,
+                        Packet::ChangeTopic {
+                            topic: topic.clone(),
+                            extra: ExtraFields::new(),
+                        },
+                    );
+                }
+            }
+            Packet::ListChannels { .. } => {
+                let channels = self
+                    .channels
+                    .keys()
+                    .map(|name| format!("{} {}", name, self.members(name).len()))
+                    .collect();
+                self.send(
+                    client_id,
+                    Packet::ListChannels {
+                        channels: Some(channels),
+                        extra: ExtraFields::new(),
+                    },
+                );
+            }
+            Packet::Chat(message) => {
+                if let Some(client) = self.clients.get_mut(&client_id) {
+                    client.nickname = Some(message.user.clone());
+                }
+
+                let recipients: Vec<usize> = match &message.directMessageTo {
+                    Some(nickname) => self
+                        .clients
+                        .iter()
+                        .filter(|(_, client)| client.nickname.as_ref() == Some(nickname))
+                        .map(|(id, _)| *id)
+                        .collect(),
+                    None => self.members(&channel),
+                };
+                if recipients.is_empty()
+                    && let Some(nickname) = &message.directMessageTo
+                {
+                    self.send_error(client_id, &format!("{nickname} is not online"), false);
+                }
+                for recipient in recipients {
+                    if recipient != client_id {
+                        self.send(recipient, Packet::Chat(message.clone()));
+                    }
+                }
+            }
+            _ => self.send_error(client_id, "Unsupported message type", false),
+        }
+    }
+}
+
+/// The state a connection is served against. Lets the mock server intercept packets.
+pub(crate) trait Hub: Send + 'static {
+    fn state(&mut self) -> &mut ServerState;
+
+    fn handle(&mut self, client_id: usize, packet: Packet) {
+        self.state().handle(client_id, packet);
+    }
+}
+
+impl Hub for ServerState {
+    fn state(&mut self) -> &mut ServerState {
+        self
+    }
+}
+
+/// Accepts connections until the returned task is dropped, which also closes every connection.
+pub(crate) fn spawn_accept_loop<H: Hub>(listener: TcpListener, hub: Arc<Mutex<H>>) -> Task<()> {
+    smol::spawn(async move {
+        // client tasks are cancelled when this task is
+        let mut client_tasks = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            client_tasks.retain(|task: &Task<()>| !task.is_finished());
+            let transport = SharedTransport::new(stream);
+            client_tasks.push(smol::spawn(serve_client(transport, hub.clone())));
+        }
+    })
+}
+
+/// Serves one client until it disconnects.
+pub(crate) async fn serve_client<H: Hub>(transport: SharedTransport, hub: Arc<Mutex<H>>) {
+    let (outgoing_tx, outgoing_rx) = unbounded::<Outgoing>();
+    let client_id = hub.lock().unwrap().state().connect(outgoing_tx);
+
+    let mut writer = transport.clone();
+    let write_task = smol::spawn(async move {
+        while let Ok(outgoing) = outgoing_rx.recv().await {
+            let mut data = match outgoing {
+                Outgoing::Packet(packet) => packet.to_bytes(),
+                Outgoing::Raw(line) => line.into_bytes(),
+                Outgoing::Close => break,
+            };
+            data.push(b'\n');
+            if writer.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown();
+    });
+
+    let mut reader = BufReader::new(transport);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        match Packet::from_bytes(line.as_bytes()) {
+            Ok(packet) => hub.lock().unwrap().handle(client_id, packet),
+            Err(err) => {
+                let state = &mut *hub.lock().unwrap();
+                state
+                    .state()
+                    .send_error(client_id, &format!("Invalid message: {err}"), false);
+            }
+        }
+    }
+
+    hub.lock().unwrap().state().disconnect(client_id);
+    write_task.cancel().await;
+}
+
+/// A running chat server. Stops when dropped.
+pub struct ChatServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<ServerState>>,
+    _accept_task: Task<()>,
+}
+
+impl ChatServer {
+    /// Starts listening on `addr` with the given `(name, topic)` channels. New clients join the
+    /// first one.
+    pub async fn start(addr: &str, channels: &[(&str, &str)]) -> io::Result<ChatServer> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(ServerState::new(channels)));
+        let accept_task = spawn_accept_loop(listener, state.clone());
+
+        Ok(ChatServer {
+            addr,
+            state,
+            _accept_task: accept_task,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.state.lock().unwrap().client_count()
+    }
+
+    /// Tells every client that the server is going away and closes their connections.
+    pub fn shutdown(&self, message: &str) {
+        let state = self.state.lock().unwrap();
+        let ids: Vec<usize> = state.clients.keys().copied().collect();
+        for client_id in ids {
+            state.send_error(client_id, message, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use smol::stream::StreamExt;
+
+    use crate::chat_event::ChatEvent;
+    use crate::chat_session::ChatSession;
+    use crate::packet_builder::PacketBuilder;
+    use crate::tcp_chat_client::TcpChatClient;
+
+    use super::*;
+
+    async fn connect(server: &ChatServer, nickname: &str) -> ChatSession {
+        ChatSession::connect(
+            &server.local_addr().to_string(),
+            PacketBuilder::new(nickname.into()),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn direct_messages_reach_only_the_recipient() {
+        let server = ChatServer::start("127.0.0.1:0", &[("main", ""), ("dogs", "")])
+            .await
+            .unwrap();
+        let alice = connect(&server, "alice").await;
+        let bob = connect(&server, "bob").await;
+        let carol = connect(&server, "carol").await;
+        let mut alice_events = pin!(alice.events());
+        let mut bob_events = pin!(bob.events());
+        let mut carol_events = pin!(carol.events());
+        for events in [&mut alice_events, &mut bob_events, &mut carol_events] {
+            events.next().await; // joined
+            events.next().await; // topic
+        }
+
+        // bob introduces himself on another channel, which is how the server learns his name
+        bob.join("dogs".into()).await.unwrap();
+        bob_events.next().await;
+        bob_events.next().await;
+        bob.send_chat("woof".into()).await.unwrap();
+
+        alice
+            .send_direct_message("bob".into(), "psst".into())
+            .await
+            .unwrap();
+        match bob_events.next().await {
+            Some(ChatEvent::MessageReceived(message)) => {
+                assert_eq!(message.message, "psst");
+                assert_eq!(message.directMessageTo.as_deref(), Some("bob"));
+            }
+            other => panic!("expected a message, got {other:?}"),
+        }
+        assert_eq!(bob.direct_messages("alice").len(), 1);
+        assert_eq!(alice.direct_messages("bob").len(), 1);
+
+        // carol is on main with alice but only sees alice's public message
+        alice.send_chat("hello everyone".into()).await.unwrap();
+        assert!(matches!(
+            carol_events.next().await,
+            Some(ChatEvent::MessageReceived(m)) if m.message == "hello everyone"
+        ));
+    }
+
+    #[tokio::test]
+    async fn direct_message_to_unknown_user_is_an_error() {
+        let server = ChatServer::start("127.0.0.1:0", &[("main", "")])
+            .await
+            .unwrap();
+        let alice = connect(&server, "alice").await;
+        let mut events = pin!(alice.events());
+        events.next().await;
+        events.next().await;
+
+        alice
+            .send_direct_message("nobody".into(), "hi".into())
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::ServerError { fatal: false, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn channel_list_has_user_counts() {
+        let server = ChatServer::start("127.0.0.1:0", &[("main", ""), ("dogs", "")])
+            .await
+            .unwrap();
+        let builder = PacketBuilder::new("alice".into());
+        let addr = server.local_addr().to_string();
+        let mut alice = TcpChatClient::connect(Some(&addr)).await.unwrap();
+        let _bob = TcpChatClient::connect(Some(&addr)).await.unwrap();
+        alice.recv().await.unwrap();
+        alice.recv().await.unwrap();
+
+        alice
+            .send(builder.join_channel("cats".into()))
+            .await
+            .unwrap();
+        alice.recv().await.unwrap();
+        alice.recv().await.unwrap();
+        alice.send(builder.list_channels()).await.unwrap();
+        match alice.recv().await.unwrap() {
+            Packet::ListChannels { channels, .. } => {
+                assert_eq!(channels.unwrap(), vec!["cats 1", "dogs 0", "main 1"]);
+            }
+            _ => panic!("should be a channel list"),
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_disconnects_clients() {
+        let server = ChatServer::start("127.0.0.1:0", &[("main", "")])
+            .await
+            .unwrap();
+        let alice = connect(&server, "alice").await;
+        let mut events = pin!(alice.events());
+        events.next().await;
+        events.next().await;
+
+        server.shutdown("Server is shutting down");
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::ServerError { fatal: true, .. })
+        ));
+        assert!(matches!(events.next().await, Some(ChatEvent::Disconnected)));
+    }
+}
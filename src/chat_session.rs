@@ -0,0 +1,1332 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_channel::{Receiver, Sender};
+use async_lock::Mutex as AsyncMutex;
+use futures_lite::Stream;
+use lazy_static::lazy_static;
+use regex::Regex;
+use uuid::Uuid;
+
+use crate::channel_list::ChannelInfo;
+use crate::chat_event::ChatEvent;
+use crate::heartbeat::Heartbeat;
+use crate::history::{Conversation, History};
+use crate::outbox::{Outbox, OutboxEntry};
+use crate::packet::{ChatMessage, Packet};
+use crate::packet_builder::PacketBuilder;
+use crate::proxy::Proxy;
+use crate::reconnect_policy::ReconnectPolicy;
+use crate::search::{Conversations, SearchHit, SearchIndex, SearchQuery};
+use crate::server_address::ConnectError;
+use crate::tcp_chat_client::TcpChatClient;
+use crate::tls::{TlsOptions, UntrustedCertificate};
+
+// idea from https://stackoverflow.com/questions/59170011/why-the-result-of-regexnew-cannot-be-assigned-to-a-constant
+lazy_static! {
+    pub static ref JOIN_CHANNEL_STATUS_REGEX: Regex =
+        Regex::new(r"^You joined the channel\s+(.+)$").unwrap();
+}
+
+/// Strips the user count from a channel name in a `ListChannels` reply, e.g. `"main 3"`.
+pub fn get_channel_name(name_with_user_count: String) -> String {
+    ChannelInfo::parse(&name_with_user_count).name
+}
+
+/// What the server has told us so far.
+#[derive(Debug, Default, Clone)]
+pub struct SessionState {
+    /// Channel the server has confirmed us being in.
+    pub channel: Option<String>,
+    pub topic: String,
+    pub channels: Vec<ChannelInfo>,
+    /// Messages per channel, in the order they were received or sent.
+    pub messages: HashMap<String, Vec<ChatMessage>>,
+    /// Direct messages per user we've talked to, both ways, in the order they were received or
+    /// sent.
+    pub direct_messages: HashMap<String, Vec<ChatMessage>>,
+    /// Channel we asked to get back into after a reconnect, until the server confirms it.
+    pub rejoining: Option<String>,
+    /// Where the server put us in the meantime, in case getting back fails.
+    joined_meanwhile: Option<String>,
+    /// Where each message is in its conversation.
+    positions: HashMap<Uuid, usize>,
+}
+
+impl SessionState {
+    /// The messages of `conversation`, in order.
+    pub fn conversation(&self, conversation: &Conversation) -> &[ChatMessage] {
+        let messages = match conversation {
+            Conversation::Channel(channel) => self.messages.get(channel),
+            Conversation::Direct(user) => self.direct_messages.get(user),
+        };
+        messages.map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Where the message with `id` is in its conversation.
+    pub fn position(&self, id: &Uuid) -> Option<usize> {
+        self.positions.get(id).copied()
+    }
+
+    fn conversation_mut(&mut self, conversation: &Conversation) -> &mut Vec<ChatMessage> {
+        match conversation {
+            Conversation::Channel(channel) => self.messages.entry(channel.clone()).or_default(),
+            Conversation::Direct(user) => self.direct_messages.entry(user.clone()).or_default(),
+        }
+    }
+
+    /// Adds `message` to the end of `conversation`.
+    fn push(&mut self, conversation: &Conversation, message: ChatMessage) {
+        let messages = self.conversation_mut(conversation);
+        let (id, position) = (message.id, messages.len());
+        messages.push(message);
+        self.positions.insert(id, position);
+    }
+
+    /// Numbers the messages of `conversation` again after they were reordered.
+    fn reindex(&mut self, conversation: &Conversation) {
+        let messages = match conversation {
+            Conversation::Channel(channel) => self.messages.get(channel),
+            Conversation::Direct(user) => self.direct_messages.get(user),
+        };
+        for (position, message) in messages.into_iter().flatten().enumerate() {
+            self.positions.insert(message.id, position);
+        }
+    }
+
+    /// Files a message we sent under its channel, or under the recipient for direct messages.
+    fn add_message(&mut self, message: ChatMessage) {
+        let conversation = match &message.directMessageTo {
+            Some(to) => Conversation::Direct(to.clone()),
+            None => Conversation::Channel(self.channel.clone().unwrap_or_default()),
+        };
+        self.push(&conversation, message);
+    }
+
+    /// Files a message we received, direct messages under their sender.
+    fn add_received_message(&mut self, message: ChatMessage) {
+        if message.directMessageTo.is_some() {
+            self.push(&Conversation::Direct(message.user.clone()), message);
+            return;
+        }
+        self.add_message(message);
+    }
+
+    /// Updates the state with a packet that was either received or sent.
+    ///
+    /// Returns `false` for packets that belong to the channel the server put us in while
+    /// [`SessionState::rejoining`], those are left out of the state.
+    fn apply(&mut self, packet: &Packet) -> bool {
+        if let Some(rejoining) = &self.rejoining {
+            match packet {
+                Packet::Status { status, .. } => {
+                    if let Some(caps) = JOIN_CHANNEL_STATUS_REGEX.captures(status.as_str()) {
+                        if &caps[1] != rejoining {
+                            self.joined_meanwhile = Some(caps[1].to_string());
+                            return false;
+                        }
+                        self.rejoining = None;
+                        self.joined_meanwhile = None;
+                    }
+                }
+                Packet::ChangeTopic { .. } => return false,
+                Packet::Chat(message) if message.directMessageTo.is_none() => return false,
+                Packet::Error { .. } => {
+                    // stay wherever the server put us
+                    self.rejoining = None;
+                    if let Some(channel) = self.joined_meanwhile.take() {
+                        self.channel = Some(channel);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match packet {
+            Packet::ListChannels {
+                channels: Some(channels),
+                ..
+            } => {
+                self.channels = channels.iter().map(|chl| ChannelInfo::parse(chl)).collect();
+            }
+            Packet::ChangeTopic { topic, .. } => {
+                self.topic = topic.clone();
+            }
+            Packet::Chat(message) => self.add_received_message(message.clone()),
+            Packet::Status { status, .. } => {
+                if let Some(caps) = JOIN_CHANNEL_STATUS_REGEX.captures(status.as_str()) {
+                    self.channel = Some(caps[1].to_string());
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+}
+
+impl Conversations for SessionState {
+    fn messages(&self, conversation: &Conversation) -> &[ChatMessage] {
+        self.conversation(conversation)
+    }
+
+    fn position(&self, id: &Uuid) -> Option<usize> {
+        SessionState::position(self, id)
+    }
+}
+
+/// Where [`ChatSession::events`] is with the connection.
+enum Link {
+    Connected,
+    /// `attempt` counts the retries since the connection was last up.
+    Connecting {
+        attempt: u32,
+        delay: Option<Duration>,
+    },
+    Failed {
+        attempt: u32,
+    },
+    GaveUp,
+}
+
+/// A connection to a chat server together with the state of the conversation.
+///
+/// Clones share the connection and the state. Packets should be received from only one clone at
+/// a time, sending works from any of them.
+#[derive(Clone)]
+pub struct ChatSession {
+    addr: String,
+    packet_builder: PacketBuilder,
+    policy: ReconnectPolicy,
+    heartbeat: Heartbeat,
+    tls: TlsOptions,
+    proxy: Option<Proxy>,
+    auto_join: Vec<String>,
+    outbox: Arc<Mutex<Outbox>>,
+    history: Option<Arc<Mutex<History>>>,
+    search: Arc<Mutex<SearchIndex>>,
+    writer: Arc<AsyncMutex<Option<TcpChatClient>>>,
+    reader: Arc<AsyncMutex<Option<TcpChatClient>>>,
+    retry_tx: Sender<()>,
+    retry_rx: Receiver<()>,
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl fmt::Debug for ChatSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChatSession")
+            .field("addr", &self.addr)
+            .field("policy", &self.policy)
+            .field("heartbeat", &self.heartbeat)
+            .field("proxy", &self.proxy.as_ref().map(Proxy::to_string))
+            .field("auto_join", &self.auto_join)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChatSession {
+    /// A session that isn't connected yet. [`ChatSession::events`] connects it.
+    pub fn new(addr: &str, packet_builder: PacketBuilder) -> ChatSession {
+        let (retry_tx, retry_rx) = async_channel::bounded(1);
+        ChatSession {
+            addr: addr.to_string(),
+            packet_builder,
+            policy: ReconnectPolicy::default(),
+            heartbeat: Heartbeat::default(),
+            tls: TlsOptions::default(),
+            proxy: None,
+            auto_join: vec![],
+            outbox: Arc::new(Mutex::new(Outbox::in_memory())),
+            history: None,
+            search: Arc::new(Mutex::new(SearchIndex::new())),
+            writer: Arc::new(AsyncMutex::new(None)),
+            reader: Arc::new(AsyncMutex::new(None)),
+            retry_tx,
+            retry_rx,
+            state: Arc::new(Mutex::new(SessionState::default())),
+        }
+    }
+
+    pub async fn connect(addr: &str, packet_builder: PacketBuilder) -> io::Result<ChatSession> {
+        let session = ChatSession::new(addr, packet_builder);
+        session.reconnect().await?;
+        Ok(session)
+    }
+
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> ChatSession {
+        self.policy = policy;
+        self
+    }
+
+    pub fn reconnect_policy(&self) -> &ReconnectPolicy {
+        &self.policy
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> ChatSession {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// How `tls://` addresses are verified.
+    pub fn with_tls_options(mut self, tls: TlsOptions) -> ChatSession {
+        self.tls = tls;
+        self
+    }
+
+    /// Connects through `proxy`, or directly with `None`.
+    pub fn with_proxy(mut self, proxy: Option<Proxy>) -> ChatSession {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Joins `channels` after connecting, unless we're getting back into a channel after a
+    /// reconnect. The first one listed is joined last, so that's where we end up.
+    pub fn with_auto_join(mut self, channels: Vec<String>) -> ChatSession {
+        self.auto_join = channels;
+        self
+    }
+
+    /// Uses `outbox` for unsent messages. Messages already in it are added to the session state.
+    pub fn with_outbox(mut self, outbox: Outbox) -> ChatSession {
+        {
+            let mut state = self.state.lock().unwrap();
+            let nickname = self.packet_builder.get_nickname();
+            let mut search = self.search.lock().unwrap();
+            for entry in outbox.entries() {
+                let conversation = Conversation::of(&entry.message, &entry.channel, &nickname);
+                search.add(conversation.clone(), &entry.message);
+                // a queued message is in the history as well
+                if state.position(&entry.message.id).is_none() {
+                    state.push(&conversation, entry.message.clone());
+                }
+            }
+        }
+        self.outbox = Arc::new(Mutex::new(outbox));
+        self
+    }
+
+    /// Keeps every message sent or received in `history`. Messages already in it come before
+    /// those of this session in the session state.
+    pub fn with_history(mut self, mut history: History) -> ChatSession {
+        {
+            let mut state = self.state.lock().unwrap();
+            let mut search = self.search.lock().unwrap();
+            for (conversation, earlier) in history.take_loaded() {
+                for message in &earlier {
+                    search.add(conversation.clone(), message);
+                }
+                let messages = state.conversation_mut(&conversation);
+                let known: HashSet<Uuid> = messages.iter().map(|message| message.id).collect();
+                let mut earlier: Vec<ChatMessage> = earlier
+                    .into_iter()
+                    .filter(|earlier| !known.contains(&earlier.id))
+                    .collect();
+                earlier.append(messages);
+                *messages = earlier;
+                state.reindex(&conversation);
+            }
+        }
+        self.history = Some(Arc::new(Mutex::new(history)));
+        self
+    }
+
+    /// Adds a message that made it into the session state to the search index and the history.
+    fn record(&self, message: &ChatMessage) {
+        let channel = self.current_channel().unwrap_or_default();
+        let conversation = Conversation::of(message, &channel, &self.packet_builder.get_nickname());
+        self.search
+            .lock()
+            .unwrap()
+            .add(conversation.clone(), message);
+        let Some(history) = &self.history else {
+            return;
+        };
+        if let Err(err) = history.lock().unwrap().add(conversation, message.clone()) {
+            println!("ChatSession: couldn't save the message to the history: {err}");
+        }
+    }
+
+    /// Merges `messages` into `channel`, e.g. from a teammate's export. Messages already there are
+    /// skipped and the channel is sorted by when its messages were sent, in the history too.
+    /// With a history, only the messages it keeps are added. Returns how many messages were new.
+    pub fn import(&self, channel: &str, messages: Vec<ChatMessage>) -> io::Result<usize> {
+        let conversation = Conversation::Channel(channel.to_string());
+        // what the history leaves out would be gone after a restart
+        let messages = match &self.history {
+            Some(history) => history
+                .lock()
+                .unwrap()
+                .merge(conversation.clone(), messages)?,
+            None => messages,
+        };
+        let mut state = self.state.lock().unwrap();
+        let known = state.conversation_mut(&conversation);
+        let mut ids: HashSet<Uuid> = known.iter().map(|message| message.id).collect();
+        let mut search = self.search.lock().unwrap();
+        let mut added = 0;
+        for message in &messages {
+            if ids.insert(message.id) {
+                search.add(conversation.clone(), message);
+                known.push(message.clone());
+                added += 1;
+            }
+        }
+        known.sort_by_key(|message| message.sent);
+        state.reindex(&conversation);
+        Ok(added)
+    }
+
+    /// Up to `limit` messages of the session and its history matching `query`, newest first,
+    /// with `context` messages around each.
+    pub fn search(&self, query: &SearchQuery, limit: usize, context: usize) -> Vec<SearchHit> {
+        let state = self.state.lock().unwrap();
+        self.search
+            .lock()
+            .unwrap()
+            .search(query, limit, context, &*state)
+    }
+
+    /// Opens a new connection to the same server. The session state is kept and the channel we
+    /// were in is joined again, see [`SessionState::rejoining`].
+    pub async fn reconnect(&self) -> io::Result<()> {
+        let client =
+            TcpChatClient::connect_via(Some(self.addr.as_str()), &self.tls, self.proxy.as_ref())
+                .await?;
+        if let Err(err) = client.set_keepalive(self.heartbeat.tcp_keepalive) {
+            println!("ChatSession: couldn't set up TCP keepalive: {err}");
+        }
+        *self.writer.lock().await = Some(client.clone());
+        *self.reader.lock().await = Some(client);
+
+        let rejoin = {
+            let mut state = self.state.lock().unwrap();
+            state.rejoining = state.channel.clone();
+            state.joined_meanwhile = None;
+            state.rejoining.clone()
+        };
+        match rejoin {
+            Some(channel) => self.join(channel).await?,
+            None => {
+                for channel in self.auto_join.iter().rev() {
+                    self.join(channel.clone()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Cuts the wait before the next reconnect attempt short, or starts over after
+    /// [`ChatEvent::GaveUp`].
+    pub fn retry_now(&self) {
+        let _ = self.retry_tx.try_send(());
+    }
+
+    pub fn address(&self) -> &str {
+        &self.addr
+    }
+
+    pub fn packet_builder(&self) -> PacketBuilder {
+        self.packet_builder.clone()
+    }
+
+    /// Receives the next packet and updates the session state with it.
+    pub async fn recv(&self) -> io::Result<Packet> {
+        self.recv_applied().await.map(|(packet, _)| packet)
+    }
+
+    /// Like [`ChatSession::recv`], also telling whether the packet made it into the state.
+    async fn recv_applied(&self) -> io::Result<(Packet, bool)> {
+        let packet = match self.reader.lock().await.as_mut() {
+            Some(client) => client.recv().await?,
+            None => return Err(not_connected()),
+        };
+        let applied = self.state.lock().unwrap().apply(&packet);
+        if applied && let Packet::Chat(message) = &packet {
+            self.record(message);
+        }
+        Ok((packet, applied))
+    }
+
+    /// Sends any packet. Chat messages are added to the current channel's messages, direct
+    /// messages to the conversation with their recipient.
+    ///
+    /// A chat message that can't go out right away, because we're offline, getting back into our
+    /// channel or still have older messages queued, is put in the [`Outbox`] instead and sent
+    /// once we're back in its channel. That counts as sent, see [`ChatSession::is_pending`].
+    pub async fn send(&self, packet: Packet) -> io::Result<()> {
+        let Packet::Chat(message) = &packet else {
+            return match self.writer.lock().await.as_ref() {
+                Some(client) => client.send(packet).await.map(|_| ()),
+                None => Err(not_connected()),
+            };
+        };
+        let message = message.clone();
+
+        // held throughout so that nothing overtakes a flush of the outbox
+        let writer = self.writer.lock().await;
+        let (channel, rejoining) = {
+            let mut state = self.state.lock().unwrap();
+            state.add_message(message.clone());
+            let channel = state.channel.clone().unwrap_or_default();
+            (channel, state.rejoining.is_some())
+        };
+        self.record(&message);
+        let queued_before = self
+            .outbox
+            .lock()
+            .unwrap()
+            .entries()
+            .iter()
+            .any(|entry| entry.can_send_from(&channel));
+        let client = match writer.as_ref() {
+            Some(client) if !rejoining && !queued_before => client,
+            _ => return self.outbox.lock().unwrap().push(channel, message),
+        };
+
+        if let Err(err) = client.send(packet).await {
+            println!("ChatSession: keeping message in the outbox: {err}");
+            self.outbox.lock().unwrap().push(channel, message)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the queued messages that belong to the current channel, oldest first.
+    pub async fn flush_outbox(&self) -> io::Result<()> {
+        let writer = self.writer.lock().await;
+        let Some(client) = writer.as_ref() else {
+            return Err(not_connected());
+        };
+        let channel = {
+            let state = self.state.lock().unwrap();
+            if state.rejoining.is_some() {
+                return Ok(());
+            }
+            state.channel.clone().unwrap_or_default()
+        };
+        let entries: Vec<OutboxEntry> = self
+            .outbox
+            .lock()
+            .unwrap()
+            .entries()
+            .iter()
+            .filter(|entry| entry.can_send_from(&channel))
+            .cloned()
+            .collect();
+        for entry in entries {
+            client.send(Packet::Chat(entry.message.clone())).await?;
+            self.outbox.lock().unwrap().remove(&entry.message.id)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the message with `id` is still waiting in the outbox.
+    pub fn is_pending(&self, id: &Uuid) -> bool {
+        self.outbox.lock().unwrap().contains(id)
+    }
+
+    /// The messages waiting in the outbox, oldest first.
+    pub fn pending(&self) -> Vec<OutboxEntry> {
+        self.outbox.lock().unwrap().entries().to_vec()
+    }
+
+    /// Sends a message to the current channel and returns it.
+    pub async fn send_chat(&self, message: String) -> io::Result<ChatMessage> {
+        let packet = self.packet_builder.chat_message(message);
+        let Packet::Chat(msg) = &packet else {
+            unreachable!("chat_message always builds a chat packet");
+        };
+        let msg = msg.clone();
+        self.send(packet).await?;
+        Ok(msg)
+    }
+
+    /// Sends a direct message to the users called `to` and returns it.
+    pub async fn send_direct_message(
+        &self,
+        to: String,
+        message: String,
+    ) -> io::Result<ChatMessage> {
+        let packet = self.packet_builder.direct_message(to, message);
+        let Packet::Chat(msg) = &packet else {
+            unreachable!("direct_message always builds a chat packet");
+        };
+        let msg = msg.clone();
+        self.send(packet).await?;
+        Ok(msg)
+    }
+
+    /// Asks the server to move us to `channel`. The channel changes once the server confirms it.
+    pub async fn join(&self, channel: String) -> io::Result<()> {
+        self.send(self.packet_builder.join_channel(channel)).await
+    }
+
+    pub async fn set_topic(&self, topic: String) -> io::Result<()> {
+        self.send(self.packet_builder.set_topic(topic)).await
+    }
+
+    /// Requests the channel list. The reply arrives through [`ChatSession::recv`].
+    pub async fn list_channels(&self) -> io::Result<()> {
+        self.send(self.packet_builder.list_channels()).await
+    }
+
+    /// Receives packets as [`ChatEvent`]s, connecting first if needed and reconnecting according
+    /// to the [`ReconnectPolicy`] whenever the connection is lost. A connection that goes quiet
+    /// is checked as set up in the [`Heartbeat`].
+    ///
+    /// The stream never ends on its own, after [`ChatEvent::GaveUp`] or
+    /// [`ChatEvent::UntrustedCertificate`] it waits for [`ChatSession::retry_now`]. Like
+    /// [`ChatSession::recv`], only one clone of the session should be receiving at a time.
+    pub fn events(&self) -> impl Stream<Item = ChatEvent> + use<> {
+        let session = self.clone();
+        futures_lite::stream::unfold(None, move |link| {
+            let session = session.clone();
+            async move {
+                let mut link = match link {
+                    Some(link) => link,
+                    None if session.reader.lock().await.is_some() => Link::Connected,
+                    None => Link::Connecting {
+                        attempt: 0,
+                        delay: None,
+                    },
+                };
+                let event = session.next_event(&mut link).await;
+                Some((event, Some(link)))
+            }
+        })
+    }
+
+    /// Closes a connection that stopped working. Sending stops until we're reconnected, so chat
+    /// messages go to the outbox instead of a dead socket.
+    async fn drop_connection(&self) {
+        if let Some(client) = self.reader.lock().await.as_ref() {
+            let _ = client.shutdown();
+        }
+        *self.writer.lock().await = None;
+    }
+
+    /// Sends the heartbeat probe, `false` when that doesn't work out within the probe timeout.
+    /// A probe given up on is still written in full, see [`TcpChatClient::send`].
+    async fn probe(&self) -> bool {
+        futures_lite::future::or(async { self.list_channels().await.is_ok() }, async {
+            crate::runtime::sleep(self.heartbeat.probe_timeout).await;
+            false
+        })
+        .await
+    }
+
+    async fn next_event(&self, link: &mut Link) -> ChatEvent {
+        // set once the heartbeat probe is out and nothing has arrived since
+        let mut probed = false;
+        loop {
+            match *link {
+                Link::Connected => {
+                    let timeout = match self.heartbeat.idle_timeout {
+                        Some(_) if probed => Some(self.heartbeat.probe_timeout),
+                        idle_timeout => idle_timeout,
+                    };
+                    let received = match timeout {
+                        Some(timeout) => {
+                            futures_lite::future::or(
+                                async { Some(self.recv_applied().await) },
+                                async {
+                                    crate::runtime::sleep(timeout).await;
+                                    None
+                                },
+                            )
+                            .await
+                        }
+                        None => Some(self.recv_applied().await),
+                    };
+                    match received {
+                        Some(Ok((packet, applied))) => {
+                            probed = false;
+                            if !applied {
+                                continue;
+                            }
+                            let event = ChatEvent::from_packet(packet);
+                            if let Some(ChatEvent::JoinedChannel(_)) = &event
+                                && let Err(err) = self.flush_outbox().await
+                            {
+                                println!("ChatSession: flushing the outbox failed: {err}");
+                            }
+                            if let Some(event) = event {
+                                return event;
+                            }
+                        }
+                        Some(Err(err)) if err.kind() == io::ErrorKind::InvalidData => {
+                            println!("ChatSession: skipping unreadable line from server: {err}");
+                        }
+                        Some(Err(_)) => {
+                            self.drop_connection().await;
+                            *link = Link::Failed { attempt: 0 };
+                            return ChatEvent::Disconnected;
+                        }
+                        None if !probed && self.probe().await => probed = true,
+                        None => {
+                            println!(
+                                "ChatSession: no answer to the heartbeat, dropping connection"
+                            );
+                            self.drop_connection().await;
+                            *link = Link::Failed { attempt: 0 };
+                            return ChatEvent::Disconnected;
+                        }
+                    }
+                }
+                Link::Failed { attempt } => {
+                    // a retry asked for before this attempt was announced shouldn't skip its wait
+                    while self.retry_rx.try_recv().is_ok() {}
+                    match self.policy.delay(attempt) {
+                        Some(delay) => {
+                            *link = Link::Connecting {
+                                attempt: attempt + 1,
+                                delay: Some(delay),
+                            };
+                            return ChatEvent::Reconnecting {
+                                attempt: attempt + 1,
+                                delay,
+                            };
+                        }
+                        None => {
+                            *link = Link::GaveUp;
+                            return ChatEvent::GaveUp;
+                        }
+                    }
+                }
+                Link::Connecting { attempt, delay } => {
+                    if let Some(delay) = delay {
+                        futures_lite::future::or(
+                            async {
+                                crate::runtime::sleep(delay).await;
+                            },
+                            async {
+                                let _ = self.retry_rx.recv().await;
+                            },
+                        )
+                        .await;
+                    }
+                    let was_connected = self.reader.lock().await.is_some();
+                    match self.reconnect().await {
+                        Ok(()) => {
+                            *link = Link::Connected;
+                            return if was_connected {
+                                ChatEvent::Reconnected
+                            } else {
+                                ChatEvent::Connected
+                            };
+                        }
+                        Err(err) => {
+                            println!("ChatSession: connecting failed: {err}");
+                            // no point in retrying before the user has decided to trust it
+                            if let Some(untrusted) = UntrustedCertificate::from_io_error(&err) {
+                                *link = Link::GaveUp;
+                                return ChatEvent::UntrustedCertificate(untrusted.clone());
+                            }
+                            *link = Link::Failed { attempt };
+                            // nor in retrying an address that can't be right
+                            if let Some(err) = ConnectError::from_io_error(&err) {
+                                if let ConnectError::InvalidAddress { .. } = err {
+                                    *link = Link::GaveUp;
+                                }
+                                return ChatEvent::ConnectFailed(err.clone());
+                            }
+                        }
+                    }
+                }
+                Link::GaveUp => {
+                    let _ = self.retry_rx.recv().await;
+                    *link = Link::Connecting {
+                        attempt: 0,
+                        delay: None,
+                    };
+                }
+            }
+        }
+    }
+
+    /// A copy of the whole session state.
+    pub fn state(&self) -> SessionState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn current_channel(&self) -> Option<String> {
+        self.state.lock().unwrap().channel.clone()
+    }
+
+    pub fn topic(&self) -> String {
+        self.state.lock().unwrap().topic.clone()
+    }
+
+    pub fn channels(&self) -> Vec<ChannelInfo> {
+        self.state.lock().unwrap().channels.clone()
+    }
+
+    pub fn messages(&self, channel: &str) -> Vec<ChatMessage> {
+        self.state
+            .lock()
+            .unwrap()
+            .messages
+            .get(channel)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The direct messages exchanged with `user`.
+    pub fn direct_messages(&self, user: &str) -> Vec<ChatMessage> {
+        self.state
+            .lock()
+            .unwrap()
+            .direct_messages
+            .get(user)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "not connected to the server")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use smol::Timer;
+    use smol::stream::StreamExt;
+
+    use crate::mock_server::MockServer;
+    use crate::packet::ExtraFields;
+
+    use super::*;
+
+    fn status(status: &str) -> Packet {
+        Packet::Status {
+            status: status.into(),
+            extra: ExtraFields::new(),
+        }
+    }
+
+    #[test]
+    fn it_strips_user_counts() {
+        assert_eq!(get_channel_name("main 3".into()), "main");
+        assert_eq!(get_channel_name("the lounge 12".into()), "the lounge");
+    }
+
+    #[test]
+    fn join_status_changes_channel() {
+        let mut state = SessionState::default();
+        state.apply(&status("Welcome to the server!"));
+        assert_eq!(state.channel, None);
+
+        state.apply(&status("You joined the channel main"));
+        assert_eq!(state.channel.as_deref(), Some("main"));
+    }
+
+    #[test]
+    fn messages_go_to_current_channel() {
+        let builder = PacketBuilder::new("test user".into());
+        let mut state = SessionState::default();
+
+        state.apply(&status("You joined the channel main"));
+        state.apply(&builder.chat_message("first".into()));
+        state.apply(&status("You joined the channel other"));
+        state.apply(&builder.chat_message("second".into()));
+
+        assert_eq!(state.messages["main"].len(), 1);
+        assert_eq!(state.messages["main"][0].message, "first");
+        assert_eq!(state.messages["other"][0].message, "second");
+    }
+
+    #[test]
+    fn direct_messages_are_kept_per_user() {
+        let alice = PacketBuilder::new("alice".into());
+        let mut state = SessionState::default();
+        state.apply(&status("You joined the channel main"));
+
+        state.apply(&alice.direct_message("bob".into(), "hi bob".into()));
+        let Packet::Chat(reply) = PacketBuilder::new("bob".into()).chat_message("hi".into()) else {
+            unreachable!()
+        };
+        state.add_message(ChatMessage {
+            directMessageTo: Some("alice".into()),
+            ..reply
+        });
+
+        // received from alice, sent to alice
+        assert_eq!(state.direct_messages["alice"].len(), 2);
+        assert!(!state.messages.contains_key("main"));
+    }
+
+    #[test]
+    fn topic_and_channels_are_tracked() {
+        let builder = PacketBuilder::new("test user".into());
+        let mut state = SessionState::default();
+
+        state.apply(&builder.set_topic("cats".into()));
+        state.apply(&Packet::ListChannels {
+            channels: Some(vec!["main 2".into(), "the lounge 1".into()]),
+            extra: ExtraFields::new(),
+        });
+
+        assert_eq!(state.topic, "cats");
+        let channels: Vec<&str> = state.channels.iter().map(|chl| chl.name.as_str()).collect();
+        assert_eq!(channels, vec!["main", "the lounge"]);
+        assert_eq!(state.channels[0].user_count, Some(2));
+    }
+
+    #[test]
+    fn rejoin_ignores_the_default_channel() {
+        let builder = PacketBuilder::new("test user".into());
+        let mut state = SessionState::default();
+        state.apply(&status("You joined the channel dogs"));
+        state.apply(&builder.set_topic("woof".into()));
+        state.rejoining = Some("dogs".into());
+
+        assert!(!state.apply(&status("You joined the channel main")));
+        assert!(!state.apply(&builder.set_topic("welcome".into())));
+        assert!(!state.apply(&builder.chat_message("hi main".into())));
+        assert_eq!(state.channel.as_deref(), Some("dogs"));
+        assert_eq!(state.topic, "woof");
+
+        assert!(state.apply(&status("You joined the channel dogs")));
+        assert_eq!(state.rejoining, None);
+        assert!(state.apply(&builder.chat_message("hi dogs".into())));
+        assert_eq!(state.messages["dogs"].len(), 1);
+        assert!(!state.messages.contains_key("main"));
+    }
+
+    #[test]
+    fn failed_rejoin_keeps_the_default_channel() {
+        let mut state = SessionState::default();
+        state.apply(&status("You joined the channel gone"));
+        state.rejoining = Some("gone".into());
+
+        state.apply(&status("You joined the channel main"));
+        assert!(state.apply(&Packet::Error {
+            error: "no such channel".into(),
+            clientshutdown: false,
+            extra: ExtraFields::new(),
+        }));
+        assert_eq!(state.rejoining, None);
+        assert_eq!(state.channel.as_deref(), Some("main"));
+    }
+
+    #[tokio::test]
+    async fn events_follow_the_conversation() {
+        let server = MockServer::start_with_channels(&[("main", "cats"), ("dogs", "woof")])
+            .await
+            .unwrap();
+        let alice = ChatSession::connect(&server.address(), PacketBuilder::new("alice".into()))
+            .await
+            .unwrap();
+        let bob = ChatSession::connect(&server.address(), PacketBuilder::new("bob".into()))
+            .await
+            .unwrap();
+        let mut alice_events = pin!(alice.events());
+        let mut bob_events = pin!(bob.events());
+
+        assert!(
+            matches!(alice_events.next().await, Some(ChatEvent::JoinedChannel(c)) if c == "main")
+        );
+        assert!(
+            matches!(alice_events.next().await, Some(ChatEvent::TopicChanged(t)) if t == "cats")
+        );
+        bob_events.next().await;
+        bob_events.next().await;
+
+        alice.send_chat("hi bob".into()).await.unwrap();
+        match bob_events.next().await {
+            Some(ChatEvent::MessageReceived(message)) => {
+                assert_eq!(message.user, "alice");
+                assert_eq!(message.message, "hi bob");
+            }
+            other => panic!("expected a message, got {other:?}"),
+        }
+        assert_eq!(alice.messages("main").len(), 1);
+        assert_eq!(bob.messages("main").len(), 1);
+
+        bob.join("dogs".into()).await.unwrap();
+        assert!(
+            matches!(bob_events.next().await, Some(ChatEvent::JoinedChannel(c)) if c == "dogs")
+        );
+        assert!(matches!(bob_events.next().await, Some(ChatEvent::TopicChanged(t)) if t == "woof"));
+        assert_eq!(bob.current_channel().as_deref(), Some("dogs"));
+        assert_eq!(bob.topic(), "woof");
+
+        bob.list_channels().await.unwrap();
+        assert!(matches!(
+            bob_events.next().await,
+            Some(ChatEvent::ChannelListUpdated(c))
+                if c.iter().map(|chl| chl.name.as_str()).eq(["dogs", "main"])
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_errors_become_events() {
+        let server = MockServer::start().await.unwrap();
+        let session = ChatSession::connect(&server.address(), PacketBuilder::new("alice".into()))
+            .await
+            .unwrap();
+        let mut events = pin!(session.events());
+        events.next().await;
+        events.next().await;
+
+        server.fail_next("Topic is too long", false);
+        session.set_topic("a".repeat(1000)).await.unwrap();
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::ServerError { message, fatal: false }) if message == "Topic is too long"
+        ));
+
+        server.inject_error("Server is shutting down", true);
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::ServerError { fatal: true, .. })
+        ));
+        assert!(matches!(events.next().await, Some(ChatEvent::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn retry_now_skips_the_wait() {
+        let server = MockServer::start().await.unwrap();
+        let session = ChatSession::new(&server.address(), PacketBuilder::new("alice".into()))
+            .with_reconnect_policy(ReconnectPolicy::fixed(Duration::from_secs(60)));
+        let mut events = pin!(session.events());
+        assert!(matches!(events.next().await, Some(ChatEvent::Connected)));
+        events.next().await;
+        events.next().await;
+
+        server.disconnect_all();
+        assert!(matches!(events.next().await, Some(ChatEvent::Disconnected)));
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::Reconnecting { attempt: 1, .. })
+        ));
+
+        session.retry_now();
+        let reconnected = smol::future::or(async { events.next().await }, async {
+            Timer::after(Duration::from_secs(5)).await;
+            None
+        })
+        .await;
+        assert!(matches!(reconnected, Some(ChatEvent::Reconnected)));
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::JoinedChannel(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_gives_up_after_max_attempts() {
+        // nothing listens on the port once the listener is dropped
+        let addr = smol::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let session = ChatSession::new(&addr.to_string(), PacketBuilder::new("alice".into()))
+            .with_reconnect_policy(ReconnectPolicy {
+                max_attempts: Some(2),
+                ..ReconnectPolicy::fixed(Duration::from_millis(10))
+            });
+        let mut events = pin!(session.events());
+
+        for expected in 1..=2 {
+            assert!(matches!(
+                events.next().await,
+                Some(ChatEvent::ConnectFailed(ConnectError::Unreachable { .. }))
+            ));
+            match events.next().await {
+                Some(ChatEvent::Reconnecting { attempt, delay }) => {
+                    assert_eq!(attempt, expected);
+                    assert_eq!(delay, Duration::from_millis(10));
+                }
+                other => panic!("expected a reconnect attempt, got {other:?}"),
+            }
+        }
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::ConnectFailed(_))
+        ));
+        assert!(matches!(events.next().await, Some(ChatEvent::GaveUp)));
+
+        session.retry_now();
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::ConnectFailed(_))
+        ));
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::Reconnecting { attempt: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalid_addresses_are_not_retried() {
+        let session = ChatSession::new("localhost:http", PacketBuilder::new("alice".into()));
+        let mut events = pin!(session.events());
+        match events.next().await {
+            Some(ChatEvent::ConnectFailed(ConnectError::InvalidAddress { address, .. })) => {
+                assert_eq!(address, "localhost:http")
+            }
+            other => panic!("expected an invalid address, got {other:?}"),
+        }
+
+        // waits for retry_now instead of reconnecting
+        let next = smol::future::or(async { events.next().await }, async {
+            Timer::after(Duration::from_millis(100)).await;
+            None
+        })
+        .await;
+        assert!(next.is_none());
+    }
+
+    #[tokio::test]
+    async fn quiet_connections_are_probed() {
+        let server = MockServer::start().await.unwrap();
+        let session = ChatSession::connect(&server.address(), PacketBuilder::new("alice".into()))
+            .await
+            .unwrap()
+            .with_heartbeat(Heartbeat {
+                idle_timeout: Some(Duration::from_millis(50)),
+                ..Heartbeat::default()
+            });
+        let mut events = pin!(session.events());
+        events.next().await;
+        events.next().await;
+
+        // the probe's answer comes through like any other channel list
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::ChannelListUpdated(_))
+        ));
+        assert!(matches!(
+            server.received()[..],
+            [Packet::ListChannels { channels: None, .. }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn unanswered_probe_drops_the_connection() {
+        let server = MockServer::start().await.unwrap();
+        let session = ChatSession::connect(&server.address(), PacketBuilder::new("alice".into()))
+            .await
+            .unwrap()
+            .with_heartbeat(Heartbeat {
+                tcp_keepalive: None,
+                idle_timeout: Some(Duration::from_millis(50)),
+                probe_timeout: Duration::from_millis(50),
+            });
+        let mut events = pin!(session.events());
+        events.next().await;
+        events.next().await;
+
+        server.go_silent();
+        assert!(matches!(events.next().await, Some(ChatEvent::Disconnected)));
+        assert_eq!(server.received().len(), 1);
+        let message = session.send_chat("anyone there?".into()).await.unwrap();
+        assert!(session.is_pending(&message.id));
+    }
+
+    #[tokio::test]
+    async fn it_rejoins_after_reconnecting() {
+        let server = MockServer::start_with_channels(&[("main", "cats"), ("dogs", "woof")])
+            .await
+            .unwrap();
+        let session = ChatSession::connect(&server.address(), PacketBuilder::new("alice".into()))
+            .await
+            .unwrap()
+            .with_reconnect_policy(ReconnectPolicy::fixed(Duration::from_millis(10)));
+        let mut events = pin!(session.events());
+        events.next().await;
+        events.next().await;
+        session.join("dogs".into()).await.unwrap();
+        events.next().await;
+        events.next().await;
+
+        server.disconnect_all();
+        assert!(matches!(events.next().await, Some(ChatEvent::Disconnected)));
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::Reconnecting { .. })
+        ));
+        assert!(matches!(events.next().await, Some(ChatEvent::Reconnected)));
+
+        // the server's own welcome to main never shows up
+        assert!(matches!(events.next().await, Some(ChatEvent::JoinedChannel(c)) if c == "dogs"));
+        assert!(matches!(events.next().await, Some(ChatEvent::TopicChanged(t)) if t == "woof"));
+        assert_eq!(session.current_channel().as_deref(), Some("dogs"));
+        assert_eq!(session.state().rejoining, None);
+    }
+
+    #[tokio::test]
+    async fn outbox_is_flushed_in_order_after_reconnecting() {
+        let server = MockServer::start().await.unwrap();
+        let session = ChatSession::connect(&server.address(), PacketBuilder::new("alice".into()))
+            .await
+            .unwrap()
+            .with_reconnect_policy(ReconnectPolicy::fixed(Duration::from_millis(50)));
+        let mut events = pin!(session.events());
+        events.next().await;
+        events.next().await;
+
+        server.disconnect_all();
+        assert!(matches!(events.next().await, Some(ChatEvent::Disconnected)));
+        let first = session.send_chat("first".into()).await.unwrap();
+        let second = session.send_chat("second".into()).await.unwrap();
+        assert!(session.is_pending(&first.id) && session.is_pending(&second.id));
+        assert_eq!(session.messages("main").len(), 2);
+
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::Reconnecting { .. })
+        ));
+        assert!(matches!(events.next().await, Some(ChatEvent::Reconnected)));
+        assert!(matches!(
+            events.next().await,
+            Some(ChatEvent::JoinedChannel(_))
+        ));
+        assert!(session.pending().is_empty());
+
+        // a message that went to the outbox is also sent after it
+        session.send_chat("third".into()).await.unwrap();
+        // the server answers in order, so everything has arrived once the list has
+        session.list_channels().await.unwrap();
+        while !matches!(events.next().await, Some(ChatEvent::ChannelListUpdated(_))) {}
+        let sent: Vec<String> = server
+            .received()
+            .into_iter()
+            .filter_map(|packet| match packet {
+                Packet::Chat(message) => Some(message.message),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent, vec!["first", "second", "third"]);
+    }
+
+    #[tokio::test]
+    async fn messages_are_kept_in_the_history() {
+        let dir = std::env::temp_dir().join(format!("neighbor_chat_history_{}", Uuid::new_v4()));
+        let server = MockServer::start().await.unwrap();
+        let alice = ChatSession::connect(&server.address(), PacketBuilder::new("alice".into()))
+            .await
+            .unwrap()
+            .with_history(History::open(&dir, None).unwrap());
+        let bob = ChatSession::connect(&server.address(), PacketBuilder::new("bob".into()))
+            .await
+            .unwrap();
+        let mut alice_events = pin!(alice.events());
+        alice_events.next().await;
+        alice_events.next().await;
+
+        alice.send_chat("hi".into()).await.unwrap();
+        bob.send_direct_message("alice".into(), "psst".into())
+            .await
+            .unwrap();
+        assert!(matches!(
+            alice_events.next().await,
+            Some(ChatEvent::MessageReceived(_))
+        ));
+
+        let history = History::open(&dir, None).unwrap();
+        let main = history.messages(&Conversation::Channel("main".into()));
+        assert_eq!(main.unwrap().len(), 1);
+        let bob = history.messages(&Conversation::Direct("bob".into()));
+        assert_eq!(bob.unwrap().len(), 1);
+
+        // loaded back before the messages of the new session
+        let mut outbox = Outbox::in_memory();
+        let earlier = alice.messages("main").remove(0);
+        outbox.push("main".into(), earlier.clone()).unwrap();
+        let session = ChatSession::new(&server.address(), PacketBuilder::new("alice".into()))
+            .with_history(history)
+            .with_outbox(outbox);
+        assert_eq!(session.messages("main"), vec![earlier]);
+        assert_eq!(session.direct_messages("bob")[0].message, "psst");
+        let hits = session.search(&SearchQuery::parse("psst from:bob").unwrap(), 10, 0);
+        assert_eq!(hits[0].conversation, Conversation::Direct("bob".into()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn imports_are_merged_in_order() {
+        let dir = std::env::temp_dir().join(format!("neighbor_chat_history_{}", Uuid::new_v4()));
+        let builder = PacketBuilder::new("alice".into());
+        let session = ChatSession::new("127.0.0.1:10000", builder.clone())
+            .with_history(History::open(&dir, None).unwrap());
+        let chat = |text: &str, sent: i64| {
+            let Packet::Chat(message) = builder.chat_message(text.into()) else {
+                unreachable!()
+            };
+            ChatMessage { sent, ..message }
+        };
+        let (first, second, third) = (chat("first", 1), chat("second", 2), chat("third", 3));
+        session.import("main", vec![second.clone()]).unwrap();
+
+        let added = session
+            .import("main", vec![third.clone(), first.clone(), second.clone()])
+            .unwrap();
+        assert_eq!(added, 2);
+        let expected = vec![first, second, third];
+        assert_eq!(session.messages("main"), expected);
+        let history = History::open(&dir, None).unwrap();
+        assert_eq!(
+            history
+                .messages(&Conversation::Channel("main".into()))
+                .unwrap(),
+            expected
+        );
+        let hits = session.search(&SearchQuery::default(), 10, 1);
+        assert_eq!(hits[0].message.message, "third");
+        assert_eq!(hits[0].before[0].message, "second");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn imports_the_history_drops_are_left_out() {
+        let dir = std::env::temp_dir().join(format!("neighbor_chat_history_{}", Uuid::new_v4()));
+        let builder = PacketBuilder::new("alice".into());
+        let session = ChatSession::new("127.0.0.1:10000", builder.clone())
+            .with_history(History::open(&dir, Some(1)).unwrap());
+        let chat = |text: &str, sent: i64| {
+            let Packet::Chat(message) = builder.chat_message(text.into()) else {
+                unreachable!()
+            };
+            ChatMessage { sent, ..message }
+        };
+        let (older, newer) = (chat("older", 1), chat("newer", 2));
+
+        let added = session
+            .import("main", vec![older.clone(), newer.clone()])
+            .unwrap();
+        assert_eq!(added, 1);
+        assert_eq!(session.messages("main"), vec![newer]);
+        assert!(
+            session
+                .search(&SearchQuery::parse("older").unwrap(), 10, 0)
+                .is_empty()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn auto_join_channels_are_joined_after_connecting() {
+        let server = MockServer::start_with_channels(&[("main", ""), ("dogs", "")])
+            .await
+            .unwrap();
+        let session = ChatSession::new(&server.address(), PacketBuilder::new("alice".into()))
+            .with_auto_join(vec!["cats".into(), "dogs".into()]);
+        let mut events = pin!(session.events());
+        assert!(matches!(events.next().await, Some(ChatEvent::Connected)));
+
+        let mut joined = vec![];
+        while joined.len() < 3 {
+            if let Some(ChatEvent::JoinedChannel(channel)) = events.next().await {
+                joined.push(channel);
+            }
+        }
+        assert_eq!(joined, ["main", "dogs", "cats"]);
+        assert_eq!(session.current_channel().as_deref(), Some("cats"));
+    }
+}
@@ -0,0 +1,78 @@
+use dioxus::prelude::*;
+
+use crate::{
+    AppState,
+    components::popup::Popup,
+    route::Route,
+    tls::{KnownHosts, UntrustedCertificate},
+};
+
+/// Asks whether to trust a TLS certificate the server presented that couldn't be verified.
+#[component]
+pub fn CertificatePrompt(untrusted: Signal<Option<UntrustedCertificate>>) -> Element {
+    let state = use_context::<AppState>();
+    let nav = navigator();
+    let mut show = use_signal(|| false);
+
+    use_effect(move || show.set(untrusted.read().is_some()));
+
+    let Some(certificate) = untrusted() else {
+        return rsx! {};
+    };
+    let (title, explanation) = match &certificate.previous {
+        Some(_) => (
+            "Certificate changed",
+            "The server presented a different certificate than before. Only trust it if you know why it changed.",
+        ),
+        None => (
+            "Unknown certificate",
+            "The server's certificate couldn't be verified. Compare its fingerprint with the one the server admin gave you.",
+        ),
+    };
+
+    rsx! {
+        Popup { show, background_closes: false,
+            p { font_size: "32px", "{title}" }
+            div { height: "1rem" }
+            p { font_size: "14px", "{explanation}" }
+            div { height: "1rem" }
+            p {
+                font_size: "12px",
+                color: "#aaa",
+                user_select: "text",
+                word_break: "break-all",
+                "{certificate.fingerprint}"
+            }
+            div { flex: "1" }
+            div { display: "flex", flex_direction: "row", width: "100%",
+                div { flex: "1" }
+                button {
+                    min_width: "6rem",
+                    onclick: move |_| {
+                        let trusted = KnownHosts::load(KnownHosts::default_path())
+                            .and_then(|mut hosts| {
+                                hosts.trust(&certificate.server, &certificate.fingerprint)
+                            });
+                        if let Err(err) = trusted {
+                            println!("failed to save the trusted certificate: {}", err);
+                            return;
+                        }
+                        untrusted.set(None);
+                        if let Some(session) = (state.session)() {
+                            session.retry_now();
+                        }
+                    },
+                    "Trust"
+                }
+                div { width: "1rem" }
+                button {
+                    min_width: "6rem",
+                    onclick: move |_| {
+                        nav.replace(Route::Login);
+                    },
+                    "Cancel"
+                }
+            }
+        }
+    }
+}
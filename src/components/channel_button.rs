@@ -1,39 +1,80 @@
-use crate::packet::Packet;
-use crate::{AppState, components::button::Button};
+use crate::{
+    AppState,
+    components::{button::Button, export_dialog::ExportDialog, unread_badge::UnreadBadge},
+};
 use dioxus::prelude::*;
 
 #[component]
-pub fn ChannelButton(name: String, active_channel: Signal<String>) -> Element {
+pub fn ChannelButton(
+    name: String,
+    active_channel: Signal<String>,
+    active_dm: Signal<Option<String>>,
+    /// Users on the channel as of the last channel list.
+    user_count: Option<usize>,
+    /// Messages that arrived while another conversation was shown.
+    unread: usize,
+    /// Unread messages that mention us.
+    mentions: usize,
+    /// Called with the channel messages were imported into.
+    on_import: EventHandler<String>,
+) -> Element {
     let state = use_context::<AppState>();
     let packet_sender = state.packet_sender;
+    let packet_builder = state.packet_builder();
 
-    let is_active_channel = name == active_channel();
+    // a direct message conversation is shown over the channel we're in
+    let is_active_channel = name == active_channel() && active_dm().is_none();
+    let mut show_export = use_signal(|| false);
+    let export_channel = name.clone();
 
     rsx! {
-        Button {
-            disabled: is_active_channel,
-            class: if is_active_channel { "neighborhood-button-current" } else { "neighborhood-button" },
-            label: name.clone(),
-            onclick: move |_evt| {
-                let chl_name = name.clone();
-                if chl_name == active_channel() {
-                    return;
+        ExportDialog { show: show_export, channel: export_channel, on_import }
+        div { display: "flex", flex_direction: "row", width: "100%",
+            Button {
+                disabled: is_active_channel,
+                class: if is_active_channel { "neighborhood-button-current" } else { "neighborhood-button" },
+                label: name.clone(),
+                onclick: move |_evt| {
+                    let chl_name = name.clone();
+                    let packet_builder = packet_builder.clone();
+                    active_dm.set(None);
+                    if chl_name == active_channel() {
+                        return;
+                    }
+                    spawn(async move {
+                        match packet_sender
+                            .unwrap()
+                            .send(packet_builder.join_channel(chl_name))
+                            .await
+                        {
+                            Ok(_) => {}
+                            Err(err) => {
+                                println!("Failed to send packet down the mpsc channel: {}", err);
+                            }
+                        };
+                    });
+                },
+            }
+            if let Some(count) = user_count {
+                p {
+                    align_self: "center",
+                    flex_shrink: "0",
+                    margin: "0px 4px",
+                    font_size: "11px",
+                    color: "#727272",
+                    title: "Users on the channel",
+                    "{count} 👤"
                 }
-                spawn(async move {
-                    match packet_sender
-                        .unwrap()
-                        .send(Packet::JoinChannel {
-                            channel: chl_name,
-                        })
-                        .await
-                    {
-                        Ok(_) => {}
-                        Err(err) => {
-                            println!("Failed to send packet down the mpsc channel: {}", err);
-                        }
-                    };
-                });
-            },
+            }
+            UnreadBadge { unread, mentions }
+            button {
+                class: if is_active_channel { "neighborhood-button-current" } else { "neighborhood-button" },
+                width: "2.3rem",
+                text_align: "center",
+                title: "Export or import…",
+                onclick: move |_| show_export.set(true),
+                "⋯"
+            }
         }
     }
 }
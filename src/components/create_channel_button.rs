@@ -1,4 +1,4 @@
-use crate::{AppState, components::button::Button};
+use crate::{AppState, channel_list::ChannelInfo, components::button::Button};
 use dioxus::prelude::*;
 
 #[component]
@@ -64,8 +64,8 @@ pub fn CreateChannelButton() -> Element {
                                     let mut channels = consume_context::<AppState>().channels;
                                     println!("created new channel {}", new_channel.clone());
 
-                                    if !channels().contains(&new_channel) {
-                                        channels.write().push(new_channel);
+                                    if !channels.read().iter().any(|chl| chl.name == new_channel) {
+                                        channels.write().push(ChannelInfo::new(new_channel));
                                     }
                                     value.set(String::from(""));
                                     show_input.set(false);
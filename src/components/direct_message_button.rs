@@ -0,0 +1,28 @@
+use crate::components::{button::Button, unread_badge::UnreadBadge};
+use dioxus::prelude::*;
+
+/// Opens the conversation with `user` in place of the channel.
+#[component]
+pub fn DirectMessageButton(
+    user: String,
+    active_dm: Signal<Option<String>>,
+    /// Messages from `user` that haven't been seen.
+    unread: usize,
+    mentions: usize,
+) -> Element {
+    let is_active = active_dm().as_ref() == Some(&user);
+
+    rsx! {
+        div { display: "flex", flex_direction: "row", width: "100%",
+            Button {
+                disabled: is_active,
+                class: if is_active { "neighborhood-button-current" } else { "neighborhood-button" },
+                label: format!("@{user}"),
+                onclick: move |_evt| {
+                    active_dm.set(Some(user.clone()));
+                },
+            }
+            UnreadBadge { unread, mentions }
+        }
+    }
+}
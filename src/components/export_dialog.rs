@@ -0,0 +1,181 @@
+use std::collections::HashSet;
+
+use chrono::NaiveDate;
+use dioxus::prelude::*;
+
+use crate::{
+    AppState,
+    components::popup::Popup,
+    export::{self, DateRange, ExportFormat},
+};
+
+fn parse_day(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()
+}
+
+/// Exports the history of `channel`, and any other channels picked, to the downloads directory,
+/// or imports a JSON lines export into `channel`.
+#[component]
+pub fn ExportDialog(
+    show: Signal<bool>,
+    channel: String,
+    /// Called with the channel messages were imported into.
+    on_import: EventHandler<String>,
+) -> Element {
+    let state = use_context::<AppState>();
+    let channels = state.channels;
+    let first_channel = channel.clone();
+    let import_channel = channel.clone();
+    let mut import_path = use_signal(String::new);
+    let mut selected = use_signal(move || HashSet::from([first_channel]));
+    let mut format = use_signal(|| ExportFormat::Markdown);
+    let mut first_day = use_signal(String::new);
+    let mut last_day = use_signal(String::new);
+    let mut result = use_signal(String::new);
+
+    use_effect(move || {
+        // every opening starts from the channel it was opened for
+        if show() {
+            selected.set(HashSet::from([channel.clone()]));
+            result.set(String::new());
+        }
+    });
+
+    rsx! {
+        Popup { show,
+            p { font_size: "24px", "Export or import" }
+            div {
+                display: "flex",
+                flex_direction: "column",
+                overflow_y: "auto",
+                max_height: "5rem",
+                font_size: "14px",
+                margin_top: "0.5rem",
+                for chl in channels().into_iter().map(|chl| chl.name) {
+                    label {
+                        input {
+                            r#type: "checkbox",
+                            checked: selected.read().contains(&chl),
+                            onchange: {
+                                let chl = chl.clone();
+                                move |event: Event<FormData>| {
+                                    if event.checked() {
+                                        selected.write().insert(chl.clone());
+                                    } else {
+                                        selected.write().remove(&chl);
+                                    }
+                                }
+                            },
+                        }
+                        " {chl}"
+                    }
+                }
+            }
+            div {
+                display: "flex",
+                flex_direction: "row",
+                gap: "4px",
+                font_size: "12px",
+                margin_top: "0.5rem",
+                align_items: "center",
+                select {
+                    onchange: move |event| {
+                        let index: usize = event.value().parse().unwrap_or(0);
+                        format.set(ExportFormat::ALL[index]);
+                    },
+                    for (index, option) in ExportFormat::ALL.iter().enumerate() {
+                        option { value: "{index}", selected: *option == format(), "{option}" }
+                    }
+                }
+                input {
+                    r#type: "date",
+                    title: "First day",
+                    value: first_day,
+                    oninput: move |event| first_day.set(event.value()),
+                }
+                "–"
+                input {
+                    r#type: "date",
+                    title: "Last day",
+                    value: last_day,
+                    oninput: move |event| last_day.set(event.value()),
+                }
+            }
+            div {
+                display: "flex",
+                flex_direction: "row",
+                gap: "4px",
+                font_size: "12px",
+                margin_top: "0.5rem",
+                input {
+                    r#type: "text",
+                    flex: "1",
+                    placeholder: "Import a .jsonl export into {import_channel}",
+                    value: import_path,
+                    oninput: move |event| import_path.set(event.value()),
+                }
+                button {
+                    disabled: import_path.read().trim().is_empty(),
+                    onclick: move |_| {
+                        let Some(session) = (state.session)() else {
+                            result.set("Not connected to a server.".to_string());
+                            return;
+                        };
+                        let imported = export::read_json_lines_file(import_path().trim())
+                            .and_then(|messages| session.import(&import_channel, messages));
+                        match imported {
+                            Ok(added) => {
+                                result.set(format!("Imported {} new messages.", added));
+                                import_path.set(String::new());
+                                on_import(import_channel.clone());
+                            }
+                            Err(err) => result.set(format!("Import failed: {}", err)),
+                        }
+                    },
+                    "Import"
+                }
+            }
+            p {
+                font_size: "12px",
+                color: "#aaa",
+                margin_top: "0.5rem",
+                user_select: "text",
+                word_break: "break-all",
+                "{result}"
+            }
+            div { flex: "1" }
+            div { display: "flex", flex_direction: "row", width: "100%",
+                div { flex: "1" }
+                button {
+                    min_width: "6rem",
+                    disabled: selected.read().is_empty(),
+                    onclick: move |_| {
+                        let Some(session) = (state.session)() else {
+                            result.set("Not connected to a server.".to_string());
+                            return;
+                        };
+                        // in the order of the sidebar
+                        let picked: Vec<String> = channels()
+                            .into_iter()
+                            .map(|chl| chl.name)
+                            .filter(|chl| selected.read().contains(chl))
+                            .collect();
+                        let histories: Vec<_> = picked
+                            .iter()
+                            .map(|chl| (chl.clone(), session.messages(chl)))
+                            .collect();
+                        let range = DateRange::days(parse_day(&first_day()), parse_day(&last_day()));
+                        let path = export::default_dir().join(export::file_name(&picked, format()));
+                        match export::export_to_file(&histories, format(), &range, &path) {
+                            Ok(()) => result.set(format!("Saved to {}", path.display())),
+                            Err(err) => result.set(format!("Export failed: {}", err)),
+                        }
+                    },
+                    "Export"
+                }
+                div { width: "1rem" }
+                button { min_width: "6rem", onclick: move |_| show.set(false), "Close" }
+            }
+        }
+    }
+}
@@ -7,6 +7,8 @@ pub fn InputField(
     label: Option<String>,
     placeholder: String,
     value: Signal<String>,
+    /// `type` of the input, `text` by default.
+    input_type: Option<String>,
     legal_regex: Option<String>,
     onblur: Option<Callback<Event<FocusData>>>,
     onillegal: Option<EventHandler>,
@@ -27,7 +29,7 @@ pub fn InputField(
                 p { text_align: "left", font_size: "12px", width: "100%", "{l}" }
             }
             input {
-                r#type: "text",
+                r#type: input_type.as_deref().unwrap_or("text"),
                 style: "width: 100%",
                 // lower level than oninput, which allows preventing the modification of the text
                 // this could be used instead
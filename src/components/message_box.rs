@@ -3,17 +3,25 @@ use tokio::sync::mpsc::Sender;
 
 use crate::{
     AppState,
+    channel_state::ChannelStates,
+    history::Conversation,
     packet::{ChatMessage, Packet},
 };
 
-fn send_message(packet_sender: Sender<Packet>, message: String) -> ChatMessage {
+fn send_message(
+    packet_sender: Sender<Packet>,
+    message: String,
+    direct_message_to: Option<String>,
+    reply_to: Option<ChatMessage>,
+) {
     let state = consume_context::<AppState>();
     let packet_builder = state.packet_builder();
-    let packet = packet_builder.chat_message(message);
-    let msg = match &packet {
-        Packet::Chat(msg) => msg.clone(),
-        _ => panic!("unreachable code"),
+    let packet = match (reply_to, direct_message_to) {
+        (Some(parent), _) => packet_builder.reply(&parent, message),
+        (None, Some(to)) => packet_builder.direct_message(to, message),
+        (None, None) => packet_builder.chat_message(message),
     };
+    // the session files it under its conversation once it's sent
     spawn(async move {
         match packet_sender.send(packet).await {
             Ok(_) => {}
@@ -22,22 +30,70 @@ fn send_message(packet_sender: Sender<Packet>, message: String) -> ChatMessage {
             }
         }
     });
-    msg
 }
 
 #[component]
 pub fn MessageBox(
     disabled: bool,
-    add_message: Callback<ChatMessage>,
     active_channel: Signal<String>,
+    /// Messages go to this user only, instead of the channel.
+    direct_message_to: Option<String>,
+    /// The message being replied to, cleared once the reply is sent.
+    replying_to: Signal<Option<ChatMessage>>,
+    /// Conversation the draft belongs to.
+    conversation: Memo<Conversation>,
+    /// Where drafts are kept while other conversations are shown.
+    channel_states: Signal<ChannelStates>,
 ) -> Element {
     let state = use_context::<AppState>();
     let packet_sender = state.packet_sender;
 
-    let mut message = use_signal(|| String::from(""));
+    let message = move || {
+        channel_states
+            .read()
+            .get(&conversation.read())
+            .map(|state| state.draft.clone())
+            .unwrap_or_default()
+    };
+    let mut set_message = move |draft: String| {
+        channel_states.write().entry(conversation()).draft = draft;
+    };
+    let placeholder = match &direct_message_to {
+        Some(user) => format!("Message @{}", user),
+        None => format!("Message {}", active_channel()),
+    };
+    let keypress_to = direct_message_to.clone();
+    let click_to = direct_message_to;
 
     rsx! {
-        div { display: "flex", justify_content: "center", width: "100%",
+        div {
+            display: "flex",
+            flex_direction: "column",
+            justify_content: "center",
+            width: "100%",
+            if let Some(parent) = replying_to() {
+                div {
+                    display: "flex",
+                    flex_direction: "row",
+                    font_size: "12px",
+                    color: "#a0a0a0",
+                    padding: "0px 0px 4px 4px",
+                    p {
+                        flex: "1",
+                        overflow: "hidden",
+                        text_overflow: "ellipsis",
+                        white_space: "nowrap",
+                        "Replying to {parent.user}: {parent.message}"
+                    }
+                    p {
+                        cursor: "pointer",
+                        padding: "0px 4px",
+                        title: "Cancel the reply",
+                        onclick: move |_| replying_to.set(None),
+                        "✕"
+                    }
+                }
+            }
             div {
                 display: "flex",
                 flex_direction: "row",
@@ -49,14 +105,14 @@ pub fn MessageBox(
                     disabled,
                     font_size: "14px",
                     rows: 1,
-                    placeholder: format!("Message {}", active_channel()),
+                    placeholder,
                     border_radius: "6px 0px 0px 6px",
                     padding_left: "1rem",
                     padding_right: "0rem",
                     vertical_align: "center",
-                    value: message.read().cloned(),
+                    value: message(),
                     oninput: move |event| {
-                        message.set(event.value());
+                        set_message(event.value());
                     },
                     onkeypress: move |event| {
                         if event.key() == Key::Enter && !event.modifiers().shift() {
@@ -72,9 +128,13 @@ pub fn MessageBox(
                             }
                             match packet_sender() {
                                 Some(packet_sender) => {
-                                    let msg = send_message(packet_sender, msg);
-                                    add_message(msg);
-                                    message.set(String::from(""));
+                                    send_message(
+                                        packet_sender,
+                                        msg,
+                                        keypress_to.clone(),
+                                        replying_to.take(),
+                                    );
+                                    set_message(String::new());
                                 }
                                 None => {
                                     println!("cant send message because packet_sender is null");
@@ -97,7 +157,7 @@ pub fn MessageBox(
                     align_items: "flex-end",
                     justify_content: "center",
 
-                    disabled: disabled || message.read().is_empty(),
+                    disabled: disabled || message().is_empty(),
                     onclick: move |_| {
                         let msg = message();
 
@@ -108,9 +168,13 @@ pub fn MessageBox(
 
                         match packet_sender() {
                             Some(packet_sender) => {
-                                let msg = send_message(packet_sender, msg);
-                                add_message(msg);
-                                message.set(String::from(""));
+                                send_message(
+                                    packet_sender,
+                                    msg,
+                                    click_to.clone(),
+                                    replying_to.take(),
+                                );
+                                set_message(String::new());
                             }
                             None => {
                                 println!("cant send message because packet_sender is null");
@@ -1,40 +1,139 @@
+use std::collections::{HashMap, HashSet};
+
 use chrono::{DateTime, Datelike, Local, Timelike};
 use dioxus::prelude::*;
+use uuid::Uuid;
 
-use crate::{AppState, packet::ChatMessage};
+use crate::{AppState, channel_state::ChannelStates, history::Conversation, packet::ChatMessage};
+
+/// The start of `text`, for quoting a message above its replies.
+fn preview(text: &str) -> String {
+    const PREVIEW_LENGTH: usize = 80;
+    let mut chars = text.chars();
+    let mut preview: String = chars.by_ref().take(PREVIEW_LENGTH).collect();
+    if chars.next().is_some() {
+        preview.push('…');
+    }
+    preview
+}
+
+fn message_element_id(id: &Uuid) -> String {
+    format!("message-{}", id)
+}
+
+/// Scrolls the message into view and flashes it so it's easy to spot.
+fn scroll_to_message(id: &Uuid) {
+    document::eval(&format!(
+        r##"let m = document.getElementById("{}");
+        m?.scrollIntoView({{ block: "center" }});
+        m?.animate([{{ backgroundColor: "#3a3a3a" }}, {{ backgroundColor: "transparent" }}], 1500);"##,
+        message_element_id(id)
+    ));
+}
 
 #[component]
-fn Message(message: UIChatMessage, is_me: bool) -> Element {
+fn Message(
+    message: UIChatMessage,
+    is_me: bool,
+    pending: bool,
+    on_user_click: EventHandler<String>,
+    on_reply: EventHandler<ChatMessage>,
+    on_toggle_thread: EventHandler<Uuid>,
+) -> Element {
     let time = message.message.datetime().unwrap();
     let time: DateTime<Local> = time.into();
     let time = format!("{:02}:{:02}", time.hour(), time.minute());
 
+    let id = message.message.id;
+    let replied_to = message.message.clone();
     let content = message.message.message;
     let user = message.message.user;
+    let clicked_user = user.clone();
     let show_user = message.show_user;
     let show_time = message.show_time;
+    let parent = message.parent;
+    let replies = message.replies;
+    let collapsed = message.collapsed;
 
     rsx! {
         div {
+            id: message_element_id(&id),
             width: "100%",
             text_wrap: "wrap",
             word_wrap: "break-word",
             font_size: "12px",
             overflow_wrap: "break-word",
             justify_items: if is_me { "end" } else { "start" },
-            if show_user {
+            if show_user && is_me {
                 p { margin: "8px 0px 2px 0px", "{user}" }
+            } else if show_user {
+                p {
+                    margin: "8px 0px 2px 0px",
+                    cursor: "pointer",
+                    title: "Send {user} a direct message",
+                    onclick: move |_| on_user_click(clicked_user.clone()),
+                    "{user}"
+                }
+            }
+            if let Some(parent) = parent {
+                p {
+                    max_width: "27rem",
+                    margin: "0px 0px 2px 0px",
+                    padding: "2px 8px",
+                    border_left: "2px solid #727272",
+                    color: "#a0a0a0",
+                    cursor: "pointer",
+                    title: "Show the message this replies to",
+                    onclick: move |_| scroll_to_message(&parent.id),
+                    "{parent.user}: {preview(&parent.message)}"
+                }
+            } else if message.message.inReplyTo.is_some() {
+                p {
+                    margin: "0px 0px 2px 0px",
+                    padding: "2px 8px",
+                    border_left: "2px solid #727272",
+                    color: "#727272",
+                    "Reply to an earlier message"
+                }
             }
             div {
                 max_width: "29rem",
                 font_size: "12px",
+                opacity: if pending { "0.6" } else { "1" },
                 background_color: "#262626",
                 border_radius: "6px",
                 padding: "8px 10px 10px 10px",
                 p { user_select: "text", white_space: "pre-line", "{content}" }
             }
-            if show_time {
-                p { margin: "2px 0px 0px 0px", color: "#727272", "{time}" }
+            div {
+                display: "flex",
+                flex_direction: "row",
+                gap: "8px",
+                margin: "2px 0px 0px 0px",
+                color: "#727272",
+                if pending {
+                    p { "Waiting to send..." }
+                } else if show_time {
+                    p { "{time}" }
+                }
+                if !pending {
+                    p {
+                        cursor: "pointer",
+                        onclick: move |_| on_reply(replied_to.clone()),
+                        "Reply"
+                    }
+                }
+                if replies > 0 {
+                    p {
+                        cursor: "pointer",
+                        onclick: move |_| on_toggle_thread(id),
+                        if collapsed {
+                            "▸ {replies} hidden"
+                        } else {
+                            "▾ {replies} in thread"
+                        }
+                    }
+                }
             }
         }
     }
@@ -44,6 +143,13 @@ fn scroll_to_anchor() {
     document::eval(r#"document.getElementById("page-anchor").scrollIntoView()"#);
 }
 
+fn scroll_to(offset: f64) {
+    document::eval(&format!(
+        r#"document.getElementById("message-history-container").scrollTop = {}"#,
+        offset
+    ));
+}
+
 // right after a message is sent, the distance is 66
 const AUTOSCROLL_THRESHOLD: f64 = 100.0;
 
@@ -76,17 +182,65 @@ struct UIChatMessage {
     message: ChatMessage,
     show_user: bool,
     show_time: bool,
+    /// The message this one replies to, if it's in the history.
+    parent: Option<ChatMessage>,
+    /// How many messages reply to this one, directly or further down the thread.
+    replies: usize,
+    /// Whether the replies are hidden.
+    collapsed: bool,
 }
 
-fn combine_messages(messages: Vec<ChatMessage>) -> Vec<UIChatMessage> {
+/// The messages `message` replies to, nearest first, as far as they're in the history.
+fn ancestors<'a>(
+    message: &'a ChatMessage,
+    by_id: &HashMap<Uuid, &'a ChatMessage>,
+) -> Vec<&'a ChatMessage> {
+    let mut ancestors = Vec::new();
+    let mut current = message;
+    while let Some(parent) = current.inReplyTo.and_then(|id| by_id.get(&id)) {
+        // a cycle can only come from a misbehaving client, but it shouldn't hang us
+        if parent.id == message.id
+            || ancestors
+                .iter()
+                .any(|seen: &&ChatMessage| seen.id == parent.id)
+        {
+            break;
+        }
+        ancestors.push(*parent);
+        current = parent;
+    }
+    ancestors
+}
+
+fn combine_messages(messages: Vec<ChatMessage>, collapsed: &HashSet<Uuid>) -> Vec<UIChatMessage> {
     if messages.is_empty() {
         return Vec::<UIChatMessage>::default();
     }
 
+    let by_id: HashMap<Uuid, &ChatMessage> = messages
+        .iter()
+        .map(|message| (message.id, message))
+        .collect();
+    let mut replies = HashMap::<Uuid, usize>::new();
+    let mut visible = Vec::new();
+    for message in messages.iter() {
+        let ancestors = ancestors(message, &by_id);
+        for ancestor in ancestors.iter() {
+            *replies.entry(ancestor.id).or_default() += 1;
+        }
+        // replies stay out of sight while any thread they're in is collapsed
+        if !ancestors
+            .iter()
+            .any(|ancestor| collapsed.contains(&ancestor.id))
+        {
+            visible.push(message);
+        }
+    }
+
     let mut out = Vec::<UIChatMessage>::new();
 
     let mut prev: Option<&mut UIChatMessage> = None;
-    let iter = messages.iter();
+    let iter = visible.into_iter();
 
     for current in iter {
         let mut show_user = true;
@@ -98,7 +252,8 @@ fn combine_messages(messages: Vec<ChatMessage>) -> Vec<UIChatMessage> {
             let is_same_time =
                 cd.minute() == pt.minute() && cd.hour() == pt.hour() && cd.day() == pt.day();
 
-            if is_same_time && current.user == prev.message.user {
+            // a reply starts a new group so its quote isn't mistaken for the previous message's
+            if is_same_time && current.user == prev.message.user && current.inReplyTo.is_none() {
                 prev.show_time = false;
                 show_user = false;
             }
@@ -108,6 +263,12 @@ fn combine_messages(messages: Vec<ChatMessage>) -> Vec<UIChatMessage> {
             message: current.clone(),
             show_user,
             show_time: true,
+            parent: current
+                .inReplyTo
+                .and_then(|id| by_id.get(&id))
+                .map(|parent| (*parent).clone()),
+            replies: replies.get(&current.id).copied().unwrap_or(0),
+            collapsed: collapsed.contains(&current.id),
         });
 
         prev = out.last_mut();
@@ -116,22 +277,80 @@ fn combine_messages(messages: Vec<ChatMessage>) -> Vec<UIChatMessage> {
 }
 
 #[component]
-pub fn MessageHistory(messages: Memo<Vec<ChatMessage>>) -> Element {
+pub fn MessageHistory(
+    messages: Memo<Vec<ChatMessage>>,
+    /// Called with the name of a user that was clicked, to start a direct message.
+    on_user_click: EventHandler<String>,
+    /// Called with the message the user wants to reply to.
+    on_reply: EventHandler<ChatMessage>,
+    /// A message to scroll to as soon as it's shown, cleared once it has been.
+    jump_to: Signal<Option<Uuid>>,
+    /// Conversation the messages are from.
+    conversation: Memo<Conversation>,
+    /// Where the scroll position is kept while other conversations are shown.
+    channel_states: Signal<ChannelStates>,
+) -> Element {
     let state = use_context::<AppState>();
     let username = state.username;
+    let pending_messages = state.pending_messages;
 
     let mut final_messages = use_signal(Vec::<UIChatMessage>::new);
+    // threads whose replies are hidden, by the id of the message they start from
+    let mut collapsed_threads = use_signal(HashSet::<Uuid>::new);
+    // only peeked, scrolling doesn't need a render
+    let mut scroll_offset = use_signal(|| None::<f64>);
+    let mut shown = use_signal(|| None::<Conversation>);
 
     use_effect(move || {
-        final_messages.set(combine_messages(messages()));
+        final_messages.set(combine_messages(messages(), &collapsed_threads.read()));
+
+        // the conversation that was shown keeps its place for when it's back
+        let conversation = conversation();
+        let previous = shown.peek().clone();
+        let restore = match previous {
+            Some(previous) if previous == conversation => None,
+            previous => {
+                if let Some(previous) = previous {
+                    channel_states.write().entry(previous).scroll_offset = *scroll_offset.peek();
+                }
+                shown.set(Some(conversation.clone()));
+                let offset = channel_states
+                    .peek()
+                    .get(&conversation)
+                    .and_then(|state| state.scroll_offset);
+                scroll_offset.set(offset);
+                Some(offset)
+            }
+        };
 
         spawn(async move {
-            if should_autoscroll().await.unwrap_or(false) {
-                scroll_to_anchor();
+            match restore {
+                Some(Some(offset)) => scroll_to(offset),
+                Some(None) => scroll_to_anchor(),
+                None => {
+                    if should_autoscroll().await.unwrap_or(false) {
+                        scroll_to_anchor();
+                    }
+                }
             }
         });
     });
 
+    use_effect(move || {
+        let Some(id) = jump_to() else {
+            return;
+        };
+        if !final_messages.read().iter().any(|m| m.message.id == id) {
+            // a reply in a collapsed thread is shown first, the effect runs again after that
+            if messages.read().iter().any(|m| m.id == id) && !collapsed_threads.peek().is_empty() {
+                collapsed_threads.write().clear();
+            }
+            return;
+        }
+        jump_to.set(None);
+        scroll_to_message(&id);
+    });
+
     rsx! {
         div {
             id: "message-history-container",
@@ -145,10 +364,26 @@ pub fn MessageHistory(messages: Memo<Vec<ChatMessage>>) -> Element {
             justify_content: "flex-start",
             align_items: "center",
             padding: "0px 100px auto 0px",
+            onscroll: move |event| {
+                let bottom = f64::from(event.scroll_height() - event.client_height());
+                let offset = event.scroll_top();
+                // at the bottom, new messages should keep it there
+                scroll_offset
+                    .set((bottom - offset >= AUTOSCROLL_THRESHOLD).then_some(offset));
+            },
             for message in final_messages.read().iter() {
                 Message {
                     message: message.clone(),
                     is_me: message.message.user == username(),
+                    pending: pending_messages.read().contains(&message.message.id),
+                    on_user_click,
+                    on_reply,
+                    on_toggle_thread: move |id| {
+                        let mut collapsed_threads = collapsed_threads.write();
+                        if !collapsed_threads.remove(&id) {
+                            collapsed_threads.insert(id);
+                        }
+                    },
                 }
             }
 
@@ -1,12 +1,17 @@
 pub mod button;
+pub mod certificate_prompt;
 pub mod channel_button;
 pub mod create_channel_button;
+pub mod direct_message_button;
+pub mod export_dialog;
 pub mod input_field;
 pub mod message_box;
 pub mod message_history;
 pub mod notification;
 pub mod popup;
 pub mod routes;
+pub mod search_panel;
 pub mod tooltip;
 pub mod topic_editor;
+pub mod unread_badge;
 pub mod user_panel;
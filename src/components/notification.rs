@@ -1,19 +1,76 @@
+use std::time::{Duration, Instant};
+
 use dioxus::prelude::*;
 
 use crate::AppState;
 
+/// Shown next to the connection notification while the session is reconnecting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectStatus {
+    pub attempt: u32,
+    pub max_attempts: Option<u32>,
+    /// `None` once the attempts have run out.
+    pub retry_at: Option<Instant>,
+}
+
 #[component]
 pub fn Notification() -> Element {
     let state = use_context::<AppState>();
     let notification = state.connection_notification;
+    let reconnect_status = state.reconnect_status;
+    let session = state.session;
+    let mut now = use_signal(Instant::now);
 
-    let class = if notification.is_empty() {
+    // ticks the countdown
+    use_effect(move || {
+        spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                if reconnect_status.read().is_some() {
+                    now.set(Instant::now());
+                }
+            }
+        });
+    });
+
+    let class = if notification.is_empty() && reconnect_status.read().is_none() {
         "notification hide"
     } else {
         "notification"
     };
 
+    let countdown = reconnect_status().map(|status| {
+        let attempts = match status.max_attempts {
+            Some(max) => format!("attempt {} of {}", status.attempt, max),
+            None => format!("attempt {}", status.attempt),
+        };
+        match status.retry_at {
+            Some(retry_at) => {
+                let secs = retry_at
+                    .saturating_duration_since(now())
+                    .as_secs_f64()
+                    .ceil();
+                format!("Retrying in {secs}s ({attempts}).")
+            }
+            None => String::from("Gave up reconnecting."),
+        }
+    });
+
     rsx! {
-        div { class, z_index: "3", "{notification}" }
+        div { class, z_index: "3",
+            "{notification}"
+            if let Some(countdown) = countdown {
+                span { margin_left: "0.5rem", "{countdown}" }
+                button {
+                    margin_left: "1rem",
+                    onclick: move |_| {
+                        if let Some(session) = session() {
+                            session.retry_now();
+                        }
+                    },
+                    "Retry now"
+                }
+            }
+        }
     }
 }
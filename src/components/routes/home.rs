@@ -1,164 +1,243 @@
-use std::{collections::HashMap, io, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    pin::pin,
+    time::{Duration, Instant},
+};
 
 use dioxus::prelude::*;
-use lazy_static::lazy_static;
-use regex::Regex;
-use tokio::sync::{mpsc::Receiver, oneshot};
-
-// idea from https://stackoverflow.com/questions/59170011/why-the-result-of-regexnew-cannot-be-assigned-to-a-constant
-lazy_static! {
-    pub static ref JOIN_CHANNEL_STATUS_REGEX: Regex =
-        Regex::new(r"^You joined the channel\s+(.+)$").unwrap();
-}
+use smol::stream::StreamExt;
+use tokio::sync::mpsc::Receiver;
+use uuid::Uuid;
 
 use crate::{
     AppState,
+    channel_list::{self, ChannelSort},
+    channel_state::ChannelStates,
+    chat_event::ChatEvent,
+    chat_session::ChatSession,
     components::{
-        channel_button::ChannelButton, create_channel_button::CreateChannelButton,
-        message_box::MessageBox, message_history::MessageHistory, popup::Popup,
-        topic_editor::TopicEditor, user_panel::UserPanel,
+        button::Button, certificate_prompt::CertificatePrompt, channel_button::ChannelButton,
+        create_channel_button::CreateChannelButton, direct_message_button::DirectMessageButton,
+        message_box::MessageBox, message_history::MessageHistory, notification::ReconnectStatus,
+        search_panel::SearchPanel, topic_editor::TopicEditor, user_panel::UserPanel,
     },
+    history::{Conversation, History},
+    outbox::Outbox,
     packet::{ChatMessage, Packet},
-    tcp_chat_client::TcpChatClient,
+    reconnect_policy::ReconnectPolicy,
+    server_address::ConnectError,
+    tls::{KnownHosts, TlsOptions, UntrustedCertificate},
 };
 
-pub fn get_channel_name(name_with_user_count: String) -> String {
-    let split = name_with_user_count.split(" ").collect::<Vec<&str>>();
-    split[..split.len() - 1].join(" ")
-}
-
-pub fn add_message_to_messages(
+/// Shows the messages the session has filed, which is where sent and received messages end up.
+fn show_messages(
+    session: &ChatSession,
     mut messages: Signal<HashMap<String, Vec<ChatMessage>>>,
-    active_channel: Signal<String>,
-) -> impl FnMut(ChatMessage) {
-    move |message| {
-        let mut messages = messages.write();
-        let Some(existing_channel) = messages.get_mut(&active_channel()) else {
-            messages.insert(active_channel(), vec![message]);
-            return;
-        };
-        existing_channel.push(message);
-    }
+    mut direct_messages: Signal<HashMap<String, Vec<ChatMessage>>>,
+) {
+    let state = session.state();
+    messages.set(state.messages);
+    direct_messages.set(state.direct_messages);
 }
 
 async fn client_connect_loop(
     mut connected: Signal<bool>,
-    active_channel: Signal<String>,
+    mut active_channel: Signal<String>,
     messages: Signal<HashMap<String, Vec<ChatMessage>>>,
-    topic: Signal<String>,
+    direct_messages: Signal<HashMap<String, Vec<ChatMessage>>>,
+    mut channel_states: Signal<ChannelStates>,
+    mut untrusted_certificate: Signal<Option<UntrustedCertificate>>,
 ) {
     let state = consume_context::<AppState>();
     let mut connection_notification = state.connection_notification;
     let mut packet_sender = state.packet_sender;
-    loop {
-        connected.set(false);
-        let client = match TcpChatClient::connect(Some(state.address.to_string().as_str())).await {
-            Ok(client) => client,
-            Err(_err) => {
-                connection_notification.set(String::from("Error connecting to the server."));
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                connected.set(false);
-                continue;
-            }
-        };
-
-        connected.set(true);
-        connection_notification.set(String::from(""));
-
-        let (send_tx, send_rx) = tokio::sync::mpsc::channel::<Packet>(100);
-        packet_sender.set(Some(send_tx));
-
-        let (tx, rx) = oneshot::channel::<()>();
-        let _client = client.clone();
-        let _read_handle = spawn(async move {
-            read_loop(
-                _client,
-                active_channel,
-                add_message_to_messages(messages, active_channel),
-                topic,
-            )
-            .await;
-            let _ = tx.send(()); // notify when read loop exits
-        });
-
-        let _client = client.clone();
-        let _write_handle = spawn(async move {
-            write_loop(_client, send_rx).await;
-        });
+    let mut channels = state.channels;
+    let mut session_signal = state.session;
+    let mut reconnect_status = state.reconnect_status;
+    let mut pending_messages = state.pending_messages;
 
-        let _ = client.send(Packet::ListChannels { channels: None }).await;
+    let policy = ReconnectPolicy {
+        max_attempts: Some(10),
+        ..ReconnectPolicy::default()
+    };
+    let address = state.address.to_string();
+    let outbox = match Outbox::open(Outbox::path_for(&address)) {
+        Ok(outbox) => outbox,
+        Err(err) => {
+            println!(
+                "failed to open the outbox, unsent messages won't be kept: {}",
+                err
+            );
+            Outbox::in_memory()
+        }
+    };
+    let history_limit = state.settings.peek().history_limit;
+    let history = match History::open(History::path_for(&address), history_limit) {
+        Ok(history) => history,
+        Err(err) => {
+            println!(
+                "failed to open the message history, messages won't be kept: {}",
+                err
+            );
+            History::in_memory(history_limit)
+        }
+    };
+    let profile = state.profile.cloned();
+    // a bundle dropped into the data directory is trusted besides the usual roots
+    let default_ca_file = neighbor_chat::data_dir().join("ca-certificates.pem");
+    let tls_options = TlsOptions {
+        ca_file: profile
+            .ca_file
+            .or(default_ca_file.exists().then_some(default_ca_file)),
+        known_hosts: profile.trust_on_first_use.then(KnownHosts::default_path),
+    };
+    let session = ChatSession::new(&address, state.packet_builder())
+        .with_reconnect_policy(policy)
+        .with_tls_options(tls_options)
+        .with_proxy(state.proxy.cloned())
+        .with_auto_join(profile.auto_join)
+        .with_history(history)
+        .with_outbox(outbox);
+    // messages of earlier sessions and those left unsent show up where they were written
+    let session_state = session.state();
+    // what was there before logging in has been read
+    channel_states.write().mark_all_read(&session_state);
+    show_messages(&session, messages, direct_messages);
+    session_signal.set(Some(session.clone()));
+    pending_messages.set(pending_ids(&session));
+    connected.set(false);
 
-        let _ = rx.await;
-        _write_handle.cancel();
-    }
-}
+    // the session keeps the same writer across reconnects so the sender can live as long as it
+    let (send_tx, send_rx) = tokio::sync::mpsc::channel::<Packet>(100);
+    packet_sender.set(Some(send_tx));
+    let _session = session.clone();
+    spawn(async move {
+        write_loop(
+            _session,
+            send_rx,
+            pending_messages,
+            messages,
+            direct_messages,
+        )
+        .await;
+    });
 
-async fn read_loop(
-    mut client: TcpChatClient,
-    mut active_channel: Signal<String>,
-    mut add_message: impl FnMut(ChatMessage),
-    mut topic: Signal<String>,
-) {
-    loop {
-        let packet = match client.recv().await {
-            Err(err) => {
-                if err.kind() == io::ErrorKind::ConnectionAborted {
-                    break;
-                } else {
-                    println!("unknown error while attempting to recv()");
-                    break;
+    let mut rejoining = false;
+    let mut events = pin!(session.events());
+    while let Some(event) = events.next().await {
+        match event {
+            ChatEvent::Connected | ChatEvent::Reconnected => {
+                connected.set(true);
+                reconnect_status.set(None);
+                // the ui stays on the old channel until the server has taken us back there
+                match session.state().rejoining {
+                    Some(channel) => {
+                        connection_notification.set(format!("Rejoining {channel}..."));
+                        rejoining = true;
+                    }
+                    None => connection_notification.set(String::from("")),
                 }
+                let _ = session.list_channels().await;
             }
-            Ok(packet) => packet,
-        };
-        // println!(
-        //     "got packet {}",
-        //     String::from_utf8(packet.into_bytes()).unwrap()
-        // );
-
-        match packet {
-            Packet::ListChannels { channels } => {
-                let Some(channels) = channels else {
-                    continue;
-                };
-                consume_context::<AppState>().channels.set(
-                    channels
-                        .iter()
-                        .map(|chl| get_channel_name(chl.to_string()))
-                        .collect(),
-                );
+            ChatEvent::Disconnected => {
+                connected.set(false);
+                connection_notification.set(String::from("Lost connection to the server."));
             }
-            Packet::ChangeTopic { topic: new_topic } => {
+            ChatEvent::ConnectFailed(err) => {
+                // says what failed, e.g. which of the server's addresses refused us
+                connected.set(false);
+                connection_notification.set(err.to_string());
+                if let ConnectError::InvalidAddress { .. } = err {
+                    reconnect_status.set(None);
+                }
+            }
+            ChatEvent::Reconnecting { attempt, delay } => {
+                if connection_notification.is_empty() {
+                    connection_notification.set(String::from("Error connecting to the server."));
+                }
+                reconnect_status.set(Some(ReconnectStatus {
+                    attempt,
+                    max_attempts: session.reconnect_policy().max_attempts,
+                    retry_at: Some(Instant::now() + delay),
+                }));
+            }
+            ChatEvent::GaveUp => {
+                if let Some(status) = reconnect_status.write().as_mut() {
+                    status.retry_at = None;
+                }
+            }
+            ChatEvent::UntrustedCertificate(certificate) => {
+                connection_notification
+                    .set(String::from("The server's certificate isn't trusted."));
+                reconnect_status.set(None);
+                untrusted_certificate.set(Some(certificate));
+            }
+            ChatEvent::ChannelListUpdated(new_channels) => channels.set(new_channels),
+            ChatEvent::TopicChanged(new_topic) => {
                 println!("NEW TOPIC: {}", new_topic);
-                topic.set(new_topic);
+                // the server sends it right after taking us into the channel
+                let channel = Conversation::Channel(active_channel.cloned());
+                channel_states.write().entry(channel).topic = Some(new_topic);
             }
-            Packet::Chat(message) => {
+            ChatEvent::MessageReceived(message) => {
                 println!("MESSAGE: [{}]: {}", message.user, message.message);
-                add_message(message)
-            }
-            Packet::Error {
-                error,
-                clientshutdown: _,
-            } => {
-                println!("got error packet!: {}", error);
+                show_messages(&session, messages, direct_messages);
             }
-            Packet::Status { status } => {
-                if let Some(caps) = JOIN_CHANNEL_STATUS_REGEX.captures(status.as_str()) {
-                    let channel_name = &caps[1];
-                    println!("STATUS: updated current channel to {}", channel_name);
-                    active_channel.set(channel_name.into());
-                } else {
-                    println!("STATUS: {}", status);
+            ChatEvent::JoinedChannel(channel_name) => {
+                println!("STATUS: updated current channel to {}", channel_name);
+                // the session has sent what was waiting for this channel
+                pending_messages.set(pending_ids(&session));
+                if rejoining {
+                    rejoining = false;
+                    connection_notification.set(String::from(""));
                 }
+                active_channel.set(channel_name);
             }
-            Packet::JoinChannel { channel: _ } => {
-                println!("received JoinChannel packet from server. weird..")
+            ChatEvent::Status(status) => {
+                println!("STATUS: {}", status);
+            }
+            ChatEvent::ServerError { message, .. } => {
+                println!("got error packet!: {}", message);
+                if rejoining {
+                    // the session stays wherever the server put us instead
+                    rejoining = false;
+                    connection_notification.set(String::from(""));
+                    if let Some(channel) = session.current_channel() {
+                        active_channel.set(channel);
+                    }
+                }
             }
         }
     }
 }
 
-async fn write_loop(client: TcpChatClient, mut outgoing_rx: Receiver<Packet>) {
+/// Asks for the channel list again, the reply comes in as an event.
+fn refresh_channels(session: Option<ChatSession>) {
+    let Some(session) = session else {
+        return;
+    };
+    spawn(async move {
+        if let Err(err) = session.list_channels().await {
+            println!("failed to refresh the channel list: {}", err);
+        }
+    });
+}
+
+fn pending_ids(session: &ChatSession) -> HashSet<Uuid> {
+    session
+        .pending()
+        .into_iter()
+        .map(|entry| entry.message.id)
+        .collect()
+}
+
+async fn write_loop(
+    session: ChatSession,
+    mut outgoing_rx: Receiver<Packet>,
+    mut pending_messages: Signal<HashSet<Uuid>>,
+    messages: Signal<HashMap<String, Vec<ChatMessage>>>,
+    direct_messages: Signal<HashMap<String, Vec<ChatMessage>>>,
+) {
     loop {
         let Some(packet) = outgoing_rx.recv().await else {
             break;
@@ -168,17 +247,13 @@ async fn write_loop(client: TcpChatClient, mut outgoing_rx: Receiver<Packet>) {
             String::from_utf8(packet.to_bytes()).unwrap()
         );
 
-        match client.send(packet).await {
-            Err(err) => {
-                if err.kind() == io::ErrorKind::ConnectionAborted {
-                    break;
-                } else {
-                    println!("unknown error while attempting to send()");
-                    break;
-                }
-            }
-            Ok(packet) => packet,
-        };
+        // chat messages that can't be sent wait in the outbox, anything else is lost but the
+        // session reconnects on its own, so keep going
+        if let Err(err) = session.send(packet).await {
+            println!("failed to send packet: {}", err);
+        }
+        show_messages(&session, messages, direct_messages);
+        pending_messages.set(pending_ids(&session));
     }
 }
 
@@ -187,44 +262,130 @@ pub fn Home() -> Element {
     let state = use_context::<AppState>();
     let connected = use_signal(|| false);
 
-    let mut show_initial_popup = use_signal(|| true);
-
     let channels = state.channels;
     let active_channel = use_signal(|| String::from(""));
-    let topic = use_signal(|| String::from(""));
+    let untrusted_certificate = use_signal(|| None::<UntrustedCertificate>);
 
+    // what the session has filed, per channel
     let messages: Signal<HashMap<String, Vec<ChatMessage>>> =
         use_signal(HashMap::<String, Vec<ChatMessage>>::new);
+    // conversations by the other user's name
+    let direct_messages: Signal<HashMap<String, Vec<ChatMessage>>> =
+        use_signal(HashMap::<String, Vec<ChatMessage>>::new);
+    // users clicked on to message them, listed before there are any messages
+    let mut opened_dms = use_signal(HashSet::<String>::new);
+    // the user whose conversation is shown instead of the channel
+    let mut active_dm = use_signal(|| None::<String>);
+    let mut replying_to = use_signal(|| None::<ChatMessage>);
+    let mut show_search = use_signal(|| false);
+    // a message to show once its conversation is open, e.g. a search result
+    let jump_to = use_signal(|| None::<Uuid>);
+    let mut channel_states = use_signal(ChannelStates::new);
+    let conversation = use_memo(move || match active_dm() {
+        Some(user) => Conversation::Direct(user),
+        None => Conversation::Channel(active_channel()),
+    });
+    // each channel shows the topic it last had until the server sends it again
+    let topic = use_memo(move || {
+        channel_states
+            .read()
+            .get(&Conversation::Channel(active_channel()))
+            .and_then(|state| state.topic.clone())
+            .unwrap_or_default()
+    });
+    use_effect(move || {
+        // nothing is read while searching
+        let reading = if show_search() {
+            None
+        } else {
+            Some(conversation())
+        };
+        let nickname = state.username.cloned();
+        let mut channel_states = channel_states.write();
+        for (channel, msgs) in messages.read().iter() {
+            let channel = Conversation::Channel(channel.clone());
+            let is_read = reading.as_ref() == Some(&channel);
+            channel_states.update(&channel, msgs, &nickname, is_read);
+        }
+        for (user, msgs) in direct_messages.read().iter() {
+            let user = Conversation::Direct(user.clone());
+            let is_read = reading.as_ref() == Some(&user);
+            channel_states.update(&user, msgs, &nickname, is_read);
+        }
+    });
+    let unread = move |conversation: Conversation| {
+        channel_states
+            .read()
+            .get(&conversation)
+            .map(|state| (state.unread, state.mentions))
+            .unwrap_or_default()
+    };
+    // a reply belongs to the conversation it was started in
+    use_effect(move || {
+        active_channel.read();
+        active_dm.read();
+        replying_to.set(None);
+    });
 
     let channel_messages = use_memo(move || {
-        if let Some(msgs) = messages.get(&active_channel()) {
+        let msgs = match active_dm() {
+            Some(user) => direct_messages.get(&user),
+            None => messages.get(&active_channel()),
+        };
+        if let Some(msgs) = msgs {
             msgs.cloned()
         } else {
             vec![]
         }
     });
+    let mut channel_filter = use_signal(String::new);
+    let mut settings = state.settings;
+    let channel_sort = use_memo(move || settings.read().channel_sort);
+    let shown_channels = use_memo(move || {
+        let messages = messages.read();
+        channel_list::sort_and_filter(
+            &channels.read(),
+            &channel_filter(),
+            channel_sort(),
+            |name| messages.get(name)?.iter().map(|message| message.sent).max(),
+        )
+    });
+    let mut dm_users: Vec<String> = direct_messages
+        .read()
+        .keys()
+        .chain(opened_dms.read().iter())
+        .cloned()
+        .collect();
+    dm_users.sort();
+    dm_users.dedup();
 
     use_future(move || async move {
-        client_connect_loop(connected, active_channel, messages, topic).await
+        client_connect_loop(
+            connected,
+            active_channel,
+            messages,
+            direct_messages,
+            channel_states,
+            untrusted_certificate,
+        )
+        .await
     });
 
-    rsx! {
-        Popup { show: show_initial_popup, background_closes: false,
-            p { font_size: "32px", "Important notice" }
-            div { height: "2rem" }
-            p { font_size: "14px",
-                "Messages can only be received and viewed during the session. Logging out will erase all messages locally."
-            }
-            div { flex: "1" }
-            div { display: "flex", flex_direction: "row", width: "100%",
-                div { flex: "1" }
-                button {
-                    min_width: "6rem",
-                    onclick: move |_| show_initial_popup.set(false),
-                    "OK"
-                }
+    use_future(move || async move {
+        // others create channels and come and go, which the list only shows when fetched again
+        let Some(interval) = settings.peek().channel_refresh_secs else {
+            return;
+        };
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval.max(1))).await;
+            if *connected.peek() {
+                refresh_channels(state.session.peek().clone());
             }
         }
+    });
+
+    rsx! {
+        CertificatePrompt { untrusted: untrusted_certificate }
         div {
             display: "flex",
             flex_direction: "row",
@@ -243,13 +404,74 @@ pub fn Home() -> Element {
                 // align_items: "center",
                 gap: "4px",
                 h2 { padding: "1rem", padding_top: "1.2rem", "Your Neighborhoods" }
+                div {
+                    display: "flex",
+                    flex_direction: "row",
+                    gap: "4px",
+                    padding: "0px 1rem",
+                    font_size: "12px",
+                    input {
+                        r#type: "text",
+                        flex: "1",
+                        min_width: "0",
+                        placeholder: "Filter",
+                        value: channel_filter,
+                        oninput: move |event| channel_filter.set(event.value()),
+                    }
+                    select {
+                        title: "Sort channels by",
+                        onchange: move |event| {
+                            let index: usize = event.value().parse().unwrap_or(0);
+                            let sort = ChannelSort::ALL[index];
+                            settings.write().channel_sort = sort;
+                            crate::save_settings(&settings.peek());
+                        },
+                        for (index, option) in ChannelSort::ALL.iter().enumerate() {
+                            option { value: "{index}", selected: *option == channel_sort(), "{option}" }
+                        }
+                    }
+                    button {
+                        title: "Refresh the channel list",
+                        disabled: !connected(),
+                        onclick: move |_| refresh_channels((state.session)()),
+                        "⟳"
+                    }
+                }
                 hr { align_self: "center" }
-                for chl in channels() {
-                    ChannelButton { active_channel, name: chl }
+                for chl in shown_channels() {
+                    ChannelButton {
+                        active_channel,
+                        active_dm,
+                        user_count: chl.user_count,
+                        unread: unread(Conversation::Channel(chl.name.clone())).0,
+                        mentions: unread(Conversation::Channel(chl.name.clone())).1,
+                        name: chl.name,
+                        on_import: move |_: String| {
+                            if let Some(session) = (state.session)() {
+                                show_messages(&session, messages, direct_messages);
+                            }
+                        },
+                    }
                 }
                 hr { align_self: "center" }
                 CreateChannelButton {
                 }
+                h2 { padding: "1rem", padding_top: "1.2rem", "Direct messages" }
+                hr { align_self: "center" }
+                for user in dm_users {
+                    DirectMessageButton {
+                        active_dm,
+                        unread: unread(Conversation::Direct(user.clone())).0,
+                        mentions: unread(Conversation::Direct(user.clone())).1,
+                        user,
+                    }
+                }
+                hr { align_self: "center" }
+                Button {
+                    class: if show_search() { "neighborhood-button-current" } else { "neighborhood-button" },
+                    label: "Search messages",
+                    onclick: move |_| show_search.toggle(),
+                }
                 div { flex: "1" }
                 hr { align_self: "center" }
                 UserPanel { connected, username: state.username }
@@ -270,24 +492,50 @@ pub fn Home() -> Element {
                     min_height: "0",
                     align_items: "center",
                     justify_content: "center",
-                    TopicEditor { topic }
-                    div {
-                        display: "flex",
-                        flex_direction: "column",
-                        width: "36rem",
-                        flex: "1",
-                        flex_shrink: "0",
-                        min_height: "0",
-                        justify_content: "center",
-                        align_items: "center",
-                        MessageHistory { messages: channel_messages }
-                        div { flex: "1" }
-                        MessageBox {
-                            disabled: false,
-                            add_message: add_message_to_messages(messages, active_channel),
+                    if let Some(user) = active_dm() {
+                        h2 { padding: "1rem", "Direct messages with {user}" }
+                    } else {
+                        TopicEditor { topic }
+                    }
+                    if show_search() {
+                        SearchPanel {
+                            show: show_search,
                             active_channel,
+                            active_dm,
+                            jump_to,
+                        }
+                    } else {
+                        div {
+                            display: "flex",
+                            flex_direction: "column",
+                            width: "36rem",
+                            flex: "1",
+                            flex_shrink: "0",
+                            min_height: "0",
+                            justify_content: "center",
+                            align_items: "center",
+                            MessageHistory {
+                                messages: channel_messages,
+                                on_user_click: move |user: String| {
+                                    opened_dms.write().insert(user.clone());
+                                    active_dm.set(Some(user));
+                                },
+                                on_reply: move |message| replying_to.set(Some(message)),
+                                jump_to,
+                                conversation,
+                                channel_states,
+                            }
+                            div { flex: "1" }
+                            MessageBox {
+                                disabled: false,
+                                active_channel,
+                                direct_message_to: active_dm(),
+                                replying_to,
+                                conversation,
+                                channel_states,
+                            }
+                            div { height: "0.4rem" }
                         }
-                        div { height: "0.4rem" }
                     }
                 }
             }
@@ -0,0 +1,160 @@
+use chrono::{DateTime, Local};
+use dioxus::prelude::*;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    components::input_field::InputField,
+    history::Conversation,
+    packet::ChatMessage,
+    search::{QueryError, SearchHit, SearchQuery},
+};
+
+const RESULT_LIMIT: usize = 100;
+// messages shown around each result
+const CONTEXT: usize = 1;
+
+fn conversation_label(conversation: &Conversation) -> String {
+    match conversation {
+        Conversation::Channel(channel) => format!("#{}", channel),
+        Conversation::Direct(user) => format!("@{}", user),
+    }
+}
+
+#[component]
+fn ContextLine(message: ChatMessage) -> Element {
+    rsx! {
+        p {
+            color: "#727272",
+            overflow: "hidden",
+            text_overflow: "ellipsis",
+            white_space: "nowrap",
+            "{message.user}: {message.message}"
+        }
+    }
+}
+
+#[component]
+fn SearchResult(hit: SearchHit, onclick: EventHandler<SearchHit>) -> Element {
+    let time = hit
+        .message
+        .datetime()
+        .map(|time| {
+            let time: DateTime<Local> = time.into();
+            time.format("%Y-%m-%d %H:%M").to_string()
+        })
+        .unwrap_or_default();
+    let clicked = hit.clone();
+
+    rsx! {
+        div {
+            width: "100%",
+            font_size: "12px",
+            background_color: "#262626",
+            border_radius: "6px",
+            padding: "8px 10px",
+            cursor: "pointer",
+            title: "Show in the conversation",
+            onclick: move |_| onclick(clicked.clone()),
+            p { color: "#a0a0a0", margin_bottom: "4px",
+                "{conversation_label(&hit.conversation)} · {time}"
+            }
+            for message in hit.before {
+                ContextLine { message }
+            }
+            p { white_space: "pre-line", "{hit.message.user}: {hit.message.message}" }
+            for message in hit.after {
+                ContextLine { message }
+            }
+        }
+    }
+}
+
+/// Searches the messages of the session and its history, and opens the conversation of the
+/// result clicked at that message.
+#[component]
+pub fn SearchPanel(
+    show: Signal<bool>,
+    active_channel: Signal<String>,
+    active_dm: Signal<Option<String>>,
+    jump_to: Signal<Option<Uuid>>,
+) -> Element {
+    let state = use_context::<AppState>();
+    let packet_sender = state.packet_sender;
+    let packet_builder = state.packet_builder();
+    let query = use_signal(String::new);
+
+    let results = use_memo(move || -> Result<Vec<SearchHit>, QueryError> {
+        let query = query();
+        if query.trim().is_empty() {
+            return Ok(vec![]);
+        }
+        let query = SearchQuery::parse(&query)?;
+        Ok(match state.session.read().as_ref() {
+            Some(session) => session.search(&query, RESULT_LIMIT, CONTEXT),
+            None => vec![],
+        })
+    });
+
+    let open = use_callback(move |hit: SearchHit| {
+        match hit.conversation {
+            Conversation::Channel(channel) => {
+                active_dm.set(None);
+                if channel != active_channel() {
+                    let packet = packet_builder.join_channel(channel);
+                    spawn(async move {
+                        let Some(packet_sender) = packet_sender() else {
+                            return;
+                        };
+                        if let Err(err) = packet_sender.send(packet).await {
+                            println!("Failed to send packet down the mpsc channel: {}", err);
+                        }
+                    });
+                }
+            }
+            Conversation::Direct(user) => active_dm.set(Some(user)),
+        }
+        // the message is scrolled to once the conversation shows it
+        jump_to.set(Some(hit.message.id));
+        show.set(false);
+    });
+
+    rsx! {
+        div {
+            display: "flex",
+            flex_direction: "column",
+            width: "36rem",
+            flex: "1",
+            min_height: "0",
+            align_items: "center",
+            gap: "8px",
+            padding_top: "1rem",
+            InputField {
+                placeholder: "Search, e.g. cats from:alice in:main after:2024-01-31 has:reply",
+                value: query,
+            }
+            match results() {
+                Err(err) => rsx! {
+                    p { font_size: "12px", color: "#a0a0a0", "{err}" }
+                },
+                Ok(hits) if hits.is_empty() && !query().trim().is_empty() => rsx! {
+                    p { font_size: "12px", color: "#a0a0a0", "No messages found." }
+                },
+                Ok(hits) => rsx! {
+                    div {
+                        display: "flex",
+                        flex_direction: "column",
+                        overflow_y: "scroll",
+                        width: "100%",
+                        flex: "1",
+                        min_height: "0",
+                        gap: "6px",
+                        for hit in hits {
+                            SearchResult { hit, onclick: open }
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
@@ -1,2 +1,2 @@
 mod component;
-pub use component::*;
\ No newline at end of file
+pub use component::*;
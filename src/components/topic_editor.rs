@@ -3,7 +3,7 @@ use dioxus::prelude::*;
 use crate::AppState;
 
 #[component]
-pub fn TopicEditor(topic: Signal<String>) -> Element {
+pub fn TopicEditor(topic: Memo<String>) -> Element {
     let mut show_topic_editor = use_signal(|| false);
     let mut new_topic = use_signal(String::default);
 
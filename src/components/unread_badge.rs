@@ -0,0 +1,31 @@
+use dioxus::prelude::*;
+
+/// How many messages of a conversation haven't been seen, highlighted when some mention us.
+#[component]
+pub fn UnreadBadge(unread: usize, mentions: usize) -> Element {
+    if unread == 0 {
+        return rsx! {};
+    }
+    let title = match mentions {
+        0 => format!("{unread} unread"),
+        _ => format!("{unread} unread, {mentions} mentioning you"),
+    };
+
+    rsx! {
+        p {
+            align_self: "center",
+            flex_shrink: "0",
+            min_width: "1.2rem",
+            margin: "0px 4px",
+            padding: "1px 6px",
+            border_radius: "10px",
+            font_size: "11px",
+            font_weight: "700",
+            text_align: "center",
+            background_color: if mentions > 0 { "#c0392b" } else { "#4a4a4a" },
+            color: "#fff",
+            title,
+            if mentions > 0 { "@{mentions}" } else { "{unread}" }
+        }
+    }
+}
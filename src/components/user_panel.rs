@@ -33,7 +33,7 @@ pub fn UserPanel(username: String, connected: Signal<bool>) -> Element {
                 div { height: "2rem" }
                 p { font_size: "18px", "Are you sure that you want to log out?" }
                 div { height: "2rem" }
-                p { font_size: "14px", "Logging out will erase all current messages." }
+                p { font_size: "14px", "Messages are kept and shown again when you log back in." }
                 div { flex: "1" }
                 div { display: "flex", flex_direction: "row", width: "100%",
                     div { flex: "1" }
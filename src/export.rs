@@ -0,0 +1,417 @@
+//! Writing channel histories out as Markdown, standalone HTML or JSON lines, and reading JSON
+//! lines back in.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, NaiveDate};
+use directories::UserDirs;
+use uuid::Uuid;
+
+use crate::packet::{ChatMessage, Packet};
+use crate::search::start_of_day;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    /// A single HTML file that looks like the message history in the app.
+    Html,
+    /// One chat packet per line, as sent to the server.
+    JsonLines,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] = [
+        ExportFormat::Markdown,
+        ExportFormat::Html,
+        ExportFormat::JsonLines,
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Html => "html",
+            ExportFormat::JsonLines => "jsonl",
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExportFormat::Markdown => "Markdown",
+            ExportFormat::Html => "HTML",
+            ExportFormat::JsonLines => "JSON Lines",
+        })
+    }
+}
+
+/// The messages to export by when they were sent. Both ends are open by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DateRange {
+    /// Unix time in milliseconds, inclusive.
+    pub start: Option<i64>,
+    /// Unix time in milliseconds, exclusive.
+    pub end: Option<i64>,
+}
+
+impl DateRange {
+    /// From the start of `first` to the end of `last`, in local time.
+    pub fn days(first: Option<NaiveDate>, last: Option<NaiveDate>) -> DateRange {
+        DateRange {
+            start: first.and_then(start_of_day),
+            end: last.and_then(|last| last.succ_opt()).and_then(start_of_day),
+        }
+    }
+
+    pub fn contains(&self, sent: i64) -> bool {
+        self.start.is_none_or(|start| sent >= start) && self.end.is_none_or(|end| sent < end)
+    }
+}
+
+/// Where exports go unless asked otherwise, the downloads directory if there is one.
+pub fn default_dir() -> PathBuf {
+    UserDirs::new()
+        .and_then(|dirs| dirs.download_dir().map(Path::to_path_buf))
+        .unwrap_or_else(|| crate::data_dir().join("exports"))
+}
+
+/// A file name for exporting `channels`, e.g. `main-2024-03-01.md`.
+pub fn file_name(channels: &[String], format: ExportFormat) -> String {
+    let name: String = channels
+        .join("-")
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    format!(
+        "{}-{}.{}",
+        name,
+        Local::now().format("%Y-%m-%d"),
+        format.extension()
+    )
+}
+
+fn format_time(message: &ChatMessage) -> String {
+    message
+        .datetime()
+        .map(|time| {
+            let time: DateTime<Local> = time.into();
+            time.format("%Y-%m-%d %H:%M").to_string()
+        })
+        .unwrap_or_default()
+}
+
+/// The start of `text`, for quoting a message above its replies.
+fn preview(text: &str) -> String {
+    const PREVIEW_LENGTH: usize = 80;
+    let mut chars = text.chars();
+    let mut preview: String = chars.by_ref().take(PREVIEW_LENGTH).collect();
+    if chars.next().is_some() {
+        preview.push('…');
+    }
+    preview.replace('\n', " ")
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// the colors of the message history in the app
+const HTML_STYLE: &str = "
+body { background-color: #1d1d1d; color: #ddd; font-family: sans-serif; font-size: 12px; }
+main { max-width: 36rem; margin: auto; }
+h2 { letter-spacing: 1.5px; font-weight: 900; font-size: 20px; margin-top: 2rem; }
+.message { margin-top: 8px; }
+.user { margin: 0px 0px 2px 0px; }
+.quote { margin: 0px 0px 2px 0px; padding: 2px 8px; border-left: 2px solid #727272; color: #a0a0a0; }
+.quote a { color: inherit; text-decoration: none; }
+.bubble { display: inline-block; max-width: 29rem; background-color: #262626; border-radius: 6px; padding: 8px 10px 10px 10px; white-space: pre-line; overflow-wrap: break-word; }
+.time { margin: 2px 0px 0px 0px; color: #727272; }
+";
+
+fn write_markdown(
+    out: &mut impl Write,
+    channel: &str,
+    messages: &[&ChatMessage],
+    parents: &HashMap<Uuid, &ChatMessage>,
+) -> io::Result<()> {
+    writeln!(out, "## #{}\n", channel)?;
+    for message in messages {
+        writeln!(out, "**{}** · {}\n", message.user, format_time(message))?;
+        if let Some(parent) = message.inReplyTo.and_then(|id| parents.get(&id)) {
+            writeln!(out, "> **{}:** {}\n", parent.user, preview(&parent.message))?;
+        }
+        // a line break in markdown takes two spaces at the end of the line
+        writeln!(out, "{}\n", message.message.replace('\n', "  \n"))?;
+    }
+    Ok(())
+}
+
+fn write_html(
+    out: &mut impl Write,
+    channel: &str,
+    messages: &[&ChatMessage],
+    parents: &HashMap<Uuid, &ChatMessage>,
+) -> io::Result<()> {
+    writeln!(out, "<h2>#{}</h2>", escape_html(channel))?;
+    for message in messages {
+        writeln!(out, r#"<div class="message" id="{}">"#, message.id)?;
+        writeln!(out, r#"<p class="user">{}</p>"#, escape_html(&message.user))?;
+        if let Some(parent) = message.inReplyTo.and_then(|id| parents.get(&id)) {
+            writeln!(
+                out,
+                r##"<p class="quote"><a href="#{}">{}: {}</a></p>"##,
+                parent.id,
+                escape_html(&parent.user),
+                escape_html(&preview(&parent.message))
+            )?;
+        }
+        writeln!(
+            out,
+            r#"<div class="bubble">{}</div>"#,
+            escape_html(&message.message)
+        )?;
+        writeln!(out, r#"<p class="time">{}</p>"#, format_time(message))?;
+        writeln!(out, "</div>")?;
+    }
+    Ok(())
+}
+
+/// Writes the messages of `channels` sent within `range`, channel by channel in the order given.
+/// Replies quote their parent even when it was sent before the range.
+pub fn export(
+    channels: &[(String, Vec<ChatMessage>)],
+    format: ExportFormat,
+    range: &DateRange,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    if format == ExportFormat::Html {
+        writeln!(out, "<!DOCTYPE html>")?;
+        writeln!(out, r#"<html><head><meta charset="utf-8">"#)?;
+        writeln!(
+            out,
+            "<title>{}</title>",
+            escape_html(&channel_list(channels))
+        )?;
+        writeln!(out, "<style>{}</style></head><body><main>", HTML_STYLE)?;
+    }
+    for (channel, messages) in channels {
+        let parents: HashMap<Uuid, &ChatMessage> = messages
+            .iter()
+            .map(|message| (message.id, message))
+            .collect();
+        let in_range: Vec<&ChatMessage> = messages
+            .iter()
+            .filter(|message| range.contains(message.sent))
+            .collect();
+        match format {
+            ExportFormat::Markdown => write_markdown(out, channel, &in_range, &parents)?,
+            ExportFormat::Html => write_html(out, channel, &in_range, &parents)?,
+            ExportFormat::JsonLines => {
+                for message in in_range {
+                    out.write_all(&Packet::Chat(message.clone()).to_bytes())?;
+                    out.write_all(b"\n")?;
+                }
+            }
+        }
+    }
+    if format == ExportFormat::Html {
+        writeln!(out, "</main></body></html>")?;
+    }
+    Ok(())
+}
+
+fn channel_list(channels: &[(String, Vec<ChatMessage>)]) -> String {
+    channels
+        .iter()
+        .map(|(channel, _)| format!("#{}", channel))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Like [`export`], into a new file at `path`.
+pub fn export_to_file(
+    channels: &[(String, Vec<ChatMessage>)],
+    format: ExportFormat,
+    range: &DateRange,
+    path: impl AsRef<Path>,
+) -> io::Result<()> {
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut out = BufWriter::new(fs::File::create(path)?);
+    export(channels, format, range, &mut out)?;
+    out.flush()
+}
+
+/// Reads the chat messages of a JSON lines file, as written by [`ExportFormat::JsonLines`].
+/// Other packets, direct messages, messages sent at a time that can't be shown and lines that
+/// can't be read are skipped.
+pub fn read_json_lines(reader: impl BufRead) -> io::Result<Vec<ChatMessage>> {
+    let mut messages = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match Packet::from_bytes(line.as_bytes()) {
+            Ok(Packet::Chat(message)) if message.directMessageTo.is_some() => {
+                println!("Import: skipping a direct message, only channels are imported");
+            }
+            Ok(Packet::Chat(message)) if message.datetime().is_none() => {
+                println!("Import: skipping a message sent at {}", message.sent);
+            }
+            Ok(Packet::Chat(message)) => messages.push(message),
+            Ok(_) => {}
+            Err(err) => println!("Import: skipping unreadable line: {err}"),
+        }
+    }
+    Ok(messages)
+}
+
+/// Like [`read_json_lines`], from the file at `path`.
+pub fn read_json_lines_file(path: impl AsRef<Path>) -> io::Result<Vec<ChatMessage>> {
+    read_json_lines(BufReader::new(fs::File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveTime, TimeZone};
+
+    use crate::packet_builder::PacketBuilder;
+
+    use super::*;
+
+    fn message(user: &str, text: &str, day: &str) -> ChatMessage {
+        let Packet::Chat(mut message) = PacketBuilder::new(user.into()).chat_message(text.into())
+        else {
+            unreachable!()
+        };
+        let noon = NaiveDate::parse_from_str(day, "%Y-%m-%d")
+            .unwrap()
+            .and_time(NaiveTime::from_hms_opt(12, 0, 0).unwrap());
+        message.sent = Local.from_local_datetime(&noon).unwrap().timestamp_millis();
+        message
+    }
+
+    fn channels() -> Vec<(String, Vec<ChatMessage>)> {
+        let question = message("alice", "anyone <here>?", "2024-03-01");
+        let mut answer = message("bob", "yes\nme", "2024-03-02");
+        answer.inReplyTo = Some(question.id);
+        vec![
+            ("main".into(), vec![question, answer]),
+            ("dogs".into(), vec![message("carol", "woof", "2024-03-03")]),
+        ]
+    }
+
+    fn export_string(format: ExportFormat, range: DateRange) -> String {
+        let mut out = vec![];
+        export(&channels(), format, &range, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn day(day: &str) -> Option<NaiveDate> {
+        NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()
+    }
+
+    #[test]
+    fn markdown_quotes_replies() {
+        let markdown = export_string(ExportFormat::Markdown, DateRange::default());
+        assert!(markdown.starts_with("## #main\n"));
+        assert!(
+            markdown.contains(
+                "**bob** · 2024-03-02 12:00\n\n> **alice:** anyone <here>?\n\nyes  \nme\n"
+            )
+        );
+        assert!(markdown.contains("## #dogs\n"));
+    }
+
+    #[test]
+    fn html_is_escaped_and_standalone() {
+        let html = export_string(ExportFormat::Html, DateRange::default());
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>#main, #dogs</title>"));
+        assert!(html.contains("anyone &lt;here&gt;?"));
+        assert!(!html.contains("<here>"));
+        assert!(html.contains(r##"<p class="quote"><a href="#"##));
+        assert!(html.trim_end().ends_with("</html>"));
+    }
+
+    #[test]
+    fn json_lines_are_packets() {
+        let range = DateRange::days(day("2024-03-02"), day("2024-03-03"));
+        let lines = export_string(ExportFormat::JsonLines, range);
+        let packets: Vec<Packet> = lines
+            .lines()
+            .map(|line| Packet::from_bytes(line.as_bytes()).unwrap())
+            .collect();
+        assert_eq!(packets.len(), 2);
+        match &packets[0] {
+            Packet::Chat(message) => {
+                assert_eq!(message.message, "yes\nme");
+                assert!(message.inReplyTo.is_some());
+            }
+            _ => panic!("should be a chat message"),
+        }
+    }
+
+    #[test]
+    fn json_lines_read_back() {
+        let channels = channels();
+        let mut lines = vec![];
+        export(
+            &channels,
+            ExportFormat::JsonLines,
+            &DateRange::default(),
+            &mut lines,
+        )
+        .unwrap();
+        lines.extend_from_slice(b"not json\n\n{\"type\": 0, \"status\": \"hello\"}\n");
+        let builder = PacketBuilder::new("bob".into());
+        let Packet::Chat(far_future) = builder.chat_message("from the future".into()) else {
+            unreachable!()
+        };
+        let far_future = Packet::Chat(ChatMessage {
+            sent: i64::MAX,
+            ..far_future
+        });
+        let direct = builder.direct_message("alice".into(), "psst".into());
+        for packet in [far_future, direct] {
+            lines.extend(packet.to_bytes());
+            lines.push(b'\n');
+        }
+        let messages = read_json_lines(lines.as_slice()).unwrap();
+        let expected: Vec<ChatMessage> = channels
+            .into_iter()
+            .flat_map(|(_, messages)| messages)
+            .collect();
+        assert_eq!(messages, expected);
+    }
+
+    #[test]
+    fn ranges_are_inclusive_days() {
+        let markdown = export_string(
+            ExportFormat::Markdown,
+            DateRange::days(day("2024-03-02"), day("2024-03-02")),
+        );
+        assert!(markdown.contains("**bob**"));
+        // the parent is quoted but not exported itself
+        assert!(!markdown.contains("**alice** ·"));
+        assert!(!markdown.contains("woof"));
+    }
+}
@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+/// How a [`ChatSession`](crate::chat_session::ChatSession) notices a connection that died
+/// without being closed, e.g. after the laptop slept or a NAT forgot about it.
+///
+/// The OS is asked to probe the peer with TCP keepalive. On top of that, when nothing has been
+/// received for `idle_timeout` the session sends a `ListChannels` request and declares the
+/// connection dead if nothing arrives within `probe_timeout` either.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heartbeat {
+    /// Idle time before the OS starts sending keepalive probes, `None` leaves keepalive off.
+    pub tcp_keepalive: Option<Duration>,
+    /// `None` turns the `ListChannels` probe off.
+    pub idle_timeout: Option<Duration>,
+    pub probe_timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            tcp_keepalive: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(60)),
+            probe_timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+impl Heartbeat {
+    /// Relies on the server closing the connection, like the client did before heartbeats.
+    pub fn disabled() -> Heartbeat {
+        Heartbeat {
+            tcp_keepalive: None,
+            idle_timeout: None,
+            probe_timeout: Duration::ZERO,
+        }
+    }
+}
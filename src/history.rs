@@ -0,0 +1,547 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use crate::packet::ChatMessage;
+
+/// How many messages per conversation are kept unless the settings say otherwise.
+pub const DEFAULT_LIMIT: usize = 5000;
+
+/// Where a message belongs: a channel, or the direct messages with another user.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Conversation {
+    Channel(String),
+    Direct(String),
+}
+
+impl Conversation {
+    /// The conversation `message` is part of, as seen by `nickname` while in `channel`. Direct
+    /// messages are filed under the other user.
+    pub fn of(message: &ChatMessage, channel: &str, nickname: &str) -> Conversation {
+        match &message.directMessageTo {
+            Some(to) if message.user == nickname => Conversation::Direct(to.clone()),
+            Some(_) => Conversation::Direct(message.user.clone()),
+            None => Conversation::Channel(channel.to_string()),
+        }
+    }
+
+    fn path_in(&self, dir: &Path) -> PathBuf {
+        let (kind, name) = match self {
+            Conversation::Channel(name) => ("channels", name),
+            Conversation::Direct(name) => ("direct", name),
+        };
+        dir.join(kind)
+            .join(format!("{}.jsonl", encode_file_name(name)))
+    }
+}
+
+/// Escapes everything but ASCII letters and digits as `_xx` so that any name is a safe file name
+/// that can be turned back into the name.
+fn encode_file_name(name: &str) -> String {
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("_{byte:02x}"));
+        }
+    }
+    encoded
+}
+
+fn decode_file_name(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'_' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[derive(Debug, Default)]
+struct Log {
+    /// The kept messages, oldest first, by id and when they were sent.
+    kept: VecDeque<(Uuid, i64)>,
+    /// Ids of the kept messages, and of those dropped for the limit since the log was loaded.
+    ids: HashSet<Uuid>,
+    /// Lines in the file, the messages dropped for the limit included until it's compacted.
+    lines_on_disk: usize,
+    /// The kept messages as they were loaded, until [`History::take_loaded`] hands them over.
+    loaded: Vec<ChatMessage>,
+}
+
+/// Reads the messages saved at `path`, and how many lines they took. Lines that can't be read
+/// are skipped.
+fn read_messages(path: &Path) -> io::Result<(Vec<ChatMessage>, usize)> {
+    let mut messages = vec![];
+    let mut lines = 0;
+    for line in BufReader::new(fs::File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        lines += 1;
+        match serde_json::from_str::<ChatMessage>(&line) {
+            Ok(message) => messages.push(message),
+            Err(err) => println!("History: skipping unreadable message: {err}"),
+        }
+    }
+    Ok((messages, lines))
+}
+
+/// Messages of earlier sessions with one server, per conversation and in the order they were
+/// received or sent. Each message is kept once, going by its id.
+///
+/// With a directory, every conversation is saved there as JSON lines that new messages are
+/// appended to. Past the limit the oldest messages are dropped, and the file is rewritten once
+/// enough of them have piled up. Only ids are kept in memory, the messages are read back from
+/// the files when they're asked for.
+#[derive(Debug, Default)]
+pub struct History {
+    dir: Option<PathBuf>,
+    limit: Option<usize>,
+    logs: HashMap<Conversation, Log>,
+}
+
+impl History {
+    /// A history that isn't saved anywhere, for when the data directory can't be used. It only
+    /// remembers which messages it has seen, at most `limit` per conversation or all of them with
+    /// `None`, and has no messages to give back.
+    pub fn in_memory(limit: Option<usize>) -> History {
+        History {
+            limit,
+            ..History::default()
+        }
+    }
+
+    /// Loads the history saved in `dir`, or starts an empty one there. Lines that can't be read
+    /// are skipped.
+    pub fn open(dir: impl Into<PathBuf>, limit: Option<usize>) -> io::Result<History> {
+        let dir = dir.into();
+        let mut history = History {
+            dir: Some(dir.clone()),
+            limit,
+            logs: HashMap::new(),
+        };
+        for kind in ["channels", "direct"] {
+            let entries = match fs::read_dir(dir.join(kind)) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            for entry in entries {
+                let path = entry?.path();
+                if path
+                    .extension()
+                    .is_none_or(|extension| extension != "jsonl")
+                {
+                    continue;
+                }
+                let Some(name) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(decode_file_name)
+                else {
+                    println!("History: skipping {}", path.display());
+                    continue;
+                };
+                let conversation = match kind {
+                    "channels" => Conversation::Channel(name),
+                    _ => Conversation::Direct(name),
+                };
+                history.load(conversation, &path)?;
+            }
+        }
+        Ok(history)
+    }
+
+    fn load(&mut self, conversation: Conversation, path: &Path) -> io::Result<()> {
+        let (messages, lines_on_disk) = read_messages(path)?;
+        let mut log = Log {
+            lines_on_disk,
+            ..Log::default()
+        };
+        for message in messages {
+            if log.ids.insert(message.id) {
+                log.kept.push_back((message.id, message.sent));
+                log.loaded.push(message);
+            }
+        }
+        self.logs.insert(conversation.clone(), log);
+        self.trim(&conversation)?;
+        let log = self.logs.get_mut(&conversation).unwrap();
+        // trimming drops the oldest, which come first in both
+        let dropped = log.loaded.len() - log.kept.len();
+        log.loaded.drain(..dropped);
+        // leftovers of an earlier limit or duplicates aren't worth keeping on disk
+        if self.logs[&conversation].lines_on_disk > self.logs[&conversation].kept.len() {
+            self.save(&conversation)?;
+        }
+        Ok(())
+    }
+
+    /// Where the history of the server at `addr` is kept, under [`crate::data_dir`].
+    pub fn path_for(addr: &str) -> PathBuf {
+        crate::data_dir()
+            .join("history")
+            .join(encode_file_name(addr))
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// The conversations that have messages, in no particular order.
+    pub fn conversations(&self) -> impl Iterator<Item = &Conversation> {
+        self.logs
+            .iter()
+            .filter(|(_, log)| !log.kept.is_empty())
+            .map(|(conversation, _)| conversation)
+    }
+
+    /// The messages of `conversation`, oldest first, read from its file. Empty for a history
+    /// that isn't saved.
+    pub fn messages(&self, conversation: &Conversation) -> io::Result<Vec<ChatMessage>> {
+        let (Some(dir), Some(log)) = (&self.dir, self.logs.get(conversation)) else {
+            return Ok(vec![]);
+        };
+        if log.kept.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut saved: HashMap<Uuid, ChatMessage> = read_messages(&conversation.path_in(dir))?
+            .0
+            .into_iter()
+            .map(|message| (message.id, message))
+            .collect();
+        Ok(log
+            .kept
+            .iter()
+            .filter_map(|(id, _)| saved.remove(id))
+            .collect())
+    }
+
+    /// The messages of every conversation as they were loaded by [`History::open`], oldest first.
+    /// They're only kept until this hands them over, after that they're read from the files.
+    pub fn take_loaded(&mut self) -> Vec<(Conversation, Vec<ChatMessage>)> {
+        self.logs
+            .iter_mut()
+            .filter(|(_, log)| !log.loaded.is_empty())
+            .map(|(conversation, log)| (conversation.clone(), std::mem::take(&mut log.loaded)))
+            .collect()
+    }
+
+    /// Whether the message was added to `conversation`, even if it has been dropped for the
+    /// limit since.
+    pub fn contains(&self, conversation: &Conversation, id: &Uuid) -> bool {
+        self.logs
+            .get(conversation)
+            .is_some_and(|log| log.ids.contains(id))
+    }
+
+    /// Adds a message to the end of `conversation`. Returns `false` for a message that's already
+    /// there, or was dropped for the limit.
+    pub fn add(&mut self, conversation: Conversation, message: ChatMessage) -> io::Result<bool> {
+        if self.limit == Some(0) {
+            return Ok(false);
+        }
+        let log = self.logs.entry(conversation.clone()).or_default();
+        if !log.ids.insert(message.id) {
+            return Ok(false);
+        }
+        log.kept.push_back((message.id, message.sent));
+        self.append(&conversation, &[message])?;
+        self.trim(&conversation)?;
+        Ok(true)
+    }
+
+    /// Merges `messages` into `conversation`, skipping those already there or dropped for the
+    /// limit, and sorts the conversation by when its messages were sent. Returns the messages
+    /// that were new and are kept, those older than the limit allows are dropped right away.
+    pub fn merge(
+        &mut self,
+        conversation: Conversation,
+        messages: impl IntoIterator<Item = ChatMessage>,
+    ) -> io::Result<Vec<ChatMessage>> {
+        if self.limit == Some(0) {
+            return Ok(vec![]);
+        }
+        let log = self.logs.entry(conversation.clone()).or_default();
+        let added: Vec<ChatMessage> = messages
+            .into_iter()
+            .filter(|message| log.ids.insert(message.id))
+            .collect();
+        if added.is_empty() {
+            return Ok(added);
+        }
+        log.kept
+            .extend(added.iter().map(|message| (message.id, message.sent)));
+        log.kept.make_contiguous().sort_by_key(|(_, sent)| *sent);
+        self.append(&conversation, &added)?;
+        self.trim(&conversation)?;
+        self.save(&conversation)?;
+        let kept: HashSet<Uuid> = self.logs[&conversation]
+            .kept
+            .iter()
+            .map(|(id, _)| *id)
+            .collect();
+        Ok(added
+            .into_iter()
+            .filter(|message| kept.contains(&message.id))
+            .collect())
+    }
+
+    /// Appends `messages` to the file of `conversation`.
+    fn append(&mut self, conversation: &Conversation, messages: &[ChatMessage]) -> io::Result<()> {
+        let (Some(dir), Some(log)) = (&self.dir, self.logs.get_mut(conversation)) else {
+            return Ok(());
+        };
+        let path = conversation.path_in(dir);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut lines = vec![];
+        for message in messages {
+            serde_json::to_writer(&mut lines, message)?;
+            lines.push(b'\n');
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&lines)?;
+        log.lines_on_disk += messages.len();
+        Ok(())
+    }
+
+    /// Drops the messages of `conversation` over the limit, rewriting its file once a quarter of
+    /// the limit has been dropped.
+    fn trim(&mut self, conversation: &Conversation) -> io::Result<()> {
+        let (Some(limit), Some(log)) = (self.limit, self.logs.get_mut(conversation)) else {
+            return Ok(());
+        };
+        if log.kept.len() > limit {
+            // the ids stay, so a dropped message isn't added again
+            log.kept.drain(..log.kept.len() - limit);
+        }
+        if log.lines_on_disk > limit + limit / 4 {
+            self.save(conversation)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the file of `conversation` with only the kept messages, in order.
+    fn save(&mut self, conversation: &Conversation) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let path = conversation.path_in(dir);
+        let messages = self.messages(conversation)?;
+        crate::write_atomically(&path, |file| {
+            for message in &messages {
+                serde_json::to_writer(&mut *file, message)?;
+                file.write_all(b"\n")?;
+            }
+            Ok(())
+        })?;
+        if let Some(log) = self.logs.get_mut(conversation) {
+            log.lines_on_disk = messages.len();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::Packet;
+    use crate::packet_builder::PacketBuilder;
+
+    use super::*;
+
+    fn message(text: &str) -> ChatMessage {
+        match PacketBuilder::new("test user".into()).chat_message(text.into()) {
+            Packet::Chat(message) => message,
+            _ => unreachable!(),
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("neighbor_chat_history_{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn file_names_round_trip() {
+        for name in ["main", "the lounge", "../etc", "ääkköset", "_5f"] {
+            let encoded = encode_file_name(name);
+            assert!(
+                encoded
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+            );
+            assert_eq!(decode_file_name(&encoded).as_deref(), Some(name));
+        }
+        assert_eq!(decode_file_name("broken_4"), None);
+    }
+
+    #[test]
+    fn direct_messages_belong_to_the_other_user() {
+        let mut dm = message("psst");
+        dm.directMessageTo = Some("bob".into());
+        assert_eq!(
+            Conversation::of(&dm, "main", "test user"),
+            Conversation::Direct("bob".into())
+        );
+        assert_eq!(
+            Conversation::of(&dm, "main", "bob"),
+            Conversation::Direct("test user".into())
+        );
+        assert_eq!(
+            Conversation::of(&message("hi"), "main", "bob"),
+            Conversation::Channel("main".into())
+        );
+    }
+
+    #[test]
+    fn it_skips_duplicates() {
+        let dir = temp_dir();
+        let mut history = History::open(&dir, None).unwrap();
+        let main = Conversation::Channel("main".into());
+        let first = message("first");
+        assert!(history.add(main.clone(), first.clone()).unwrap());
+        assert!(!history.add(main.clone(), first.clone()).unwrap());
+        assert!(history.contains(&main, &first.id));
+        assert_eq!(history.messages(&main).unwrap(), [first]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_the_newest_messages() {
+        let dir = temp_dir();
+        let mut history = History::open(&dir, Some(2)).unwrap();
+        let main = Conversation::Channel("main".into());
+        let messages: Vec<ChatMessage> = (0..3).map(|i| message(&i.to_string())).collect();
+        for message in &messages {
+            history.add(main.clone(), message.clone()).unwrap();
+        }
+        assert_eq!(history.messages(&main).unwrap(), &messages[1..]);
+        assert!(!history.add(main.clone(), messages[0].clone()).unwrap());
+        assert!(
+            history
+                .merge(main.clone(), [messages[0].clone()])
+                .unwrap()
+                .is_empty()
+        );
+        // older than everything kept, so it doesn't make the cut
+        let ancient = ChatMessage {
+            sent: messages[0].sent - 60_000,
+            ..message("ancient")
+        };
+        assert!(history.merge(main.clone(), [ancient]).unwrap().is_empty());
+        assert_eq!(history.messages(&main).unwrap(), &messages[1..]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn messages_from_a_clock_behind_are_kept() {
+        let dir = temp_dir();
+        let main = Conversation::Channel("main".into());
+        let sent_at = |text: &str, sent: i64| ChatMessage {
+            sent,
+            ..message(text)
+        };
+        let mut history = History::open(&dir, Some(2)).unwrap();
+        for (text, sent) in [("first", 1000), ("second", 2000), ("third", 3000)] {
+            history.add(main.clone(), sent_at(text, sent)).unwrap();
+        }
+
+        // older than the dropped message, but never seen before
+        let behind = sent_at("behind", 500);
+        assert!(history.add(main.clone(), behind.clone()).unwrap());
+        let texts = |history: &History| -> Vec<String> {
+            let messages = history.messages(&main).unwrap();
+            messages
+                .into_iter()
+                .map(|message| message.message)
+                .collect()
+        };
+        assert_eq!(texts(&history), ["third", "behind"]);
+        assert_eq!(
+            texts(&History::open(&dir, Some(2)).unwrap()),
+            ["third", "behind"]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merged_messages_are_sorted() {
+        let dir = temp_dir();
+        let main = Conversation::Channel("main".into());
+        let mut older = message("older");
+        older.sent -= 60_000;
+        let newer = message("newer");
+        let mut history = History::open(&dir, None).unwrap();
+        history.add(main.clone(), newer.clone()).unwrap();
+
+        let added = history
+            .merge(main.clone(), [newer.clone(), older.clone(), older.clone()])
+            .unwrap();
+        assert_eq!(added, [older.clone()]);
+        assert_eq!(
+            history.messages(&main).unwrap(),
+            [older.clone(), newer.clone()]
+        );
+        assert_eq!(
+            History::open(&dir, None).unwrap().messages(&main).unwrap(),
+            [older, newer]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_survives_reopening() {
+        let dir = temp_dir();
+        let lounge = Conversation::Channel("the lounge".into());
+        let bob = Conversation::Direct("bob".into());
+        {
+            let mut history = History::open(&dir, Some(4)).unwrap();
+            for i in 0..10 {
+                history
+                    .add(lounge.clone(), message(&i.to_string()))
+                    .unwrap();
+            }
+            history.add(bob.clone(), message("psst")).unwrap();
+        }
+
+        let history = History::open(&dir, Some(3)).unwrap();
+        let mut conversations: Vec<&Conversation> = history.conversations().collect();
+        conversations.sort();
+        assert_eq!(conversations, [&lounge, &bob]);
+        let messages = history.messages(&lounge).unwrap();
+        let texts: Vec<&str> = messages
+            .iter()
+            .map(|message| message.message.as_str())
+            .collect();
+        assert_eq!(texts, ["7", "8", "9"]);
+        assert_eq!(history.messages(&bob).unwrap()[0].message, "psst");
+        let mut history = history;
+        let mut loaded = history.take_loaded();
+        loaded.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(loaded[0].0, lounge);
+        assert_eq!(loaded[0].1, messages);
+        assert!(history.take_loaded().is_empty());
+
+        // the file was compacted down to the new limit
+        let lines = fs::read_to_string(lounge.path_in(&dir)).unwrap();
+        assert_eq!(lines.lines().count(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,37 @@
+use std::fs;
+use std::io;
 use std::path::Path;
 use std::sync::LazyLock;
 
 use directories::ProjectDirs;
 
+#[cfg(all(feature = "web", target_arch = "wasm32"))]
+pub mod browser_websocket;
+pub mod channel_list;
+pub mod channel_state;
+pub mod chat_event;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod chat_server;
+pub mod chat_session;
+pub mod export;
+pub mod heartbeat;
+pub mod history;
+#[cfg(all(any(test, feature = "mock-server"), not(target_arch = "wasm32")))]
+pub mod mock_server;
+pub mod outbox;
 pub mod packet;
 pub mod packet_builder;
+pub mod proxy;
+pub mod reconnect_policy;
+pub mod runtime;
+pub mod search;
+pub mod server_address;
+pub mod settings;
 pub mod tcp_chat_client;
+pub mod tls;
+pub mod transport;
+#[cfg(all(feature = "web", not(target_arch = "wasm32")))]
+pub mod websocket;
 
 static PROJECT_DIRS: LazyLock<ProjectDirs> =
     LazyLock::new(|| ProjectDirs::from("", "jonsetzky", "Neighbor Chat").unwrap());
@@ -13,3 +39,25 @@ static PROJECT_DIRS: LazyLock<ProjectDirs> =
 pub fn data_dir() -> &'static Path {
     PROJECT_DIRS.data_dir()
 }
+
+/// Replaces the file at `path` with what `write` writes. It's written next to the old file and
+/// renamed over it, so a crash leaves either the old or the new file, never half of one.
+pub(crate) fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut fs::File) -> io::Result<()>,
+) -> io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    write(&mut file)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    // the rename is only on disk once the directory is
+    #[cfg(unix)]
+    fs::File::open(dir.unwrap_or(Path::new(".")))?.sync_all()?;
+    Ok(())
+}
@@ -1,30 +1,54 @@
 extern crate directories;
+use std::collections::HashSet;
 use std::fs;
 
 use dioxus::prelude::*;
 use dioxus_desktop::{Config, LogicalSize, WindowBuilder, use_window};
 
 mod components;
-mod packet;
-mod packet_builder;
-mod tcp_chat_client;
-
 mod route;
 
+// the protocol lives in the library crate, re-exported so the ui can refer to it through `crate::`
+use neighbor_chat::{
+    channel_list, channel_state, chat_event, chat_session, export, history, outbox, packet,
+    packet_builder, proxy, reconnect_policy, search, server_address, settings, tls,
+};
+
 use tokio::sync::mpsc::Sender;
+use uuid::Uuid;
 
 use crate::{
-    components::notification::Notification, packet::Packet, packet_builder::PacketBuilder,
+    channel_list::ChannelInfo,
+    chat_session::ChatSession,
+    components::notification::{Notification, ReconnectStatus},
+    packet::Packet,
+    packet_builder::PacketBuilder,
+    proxy::Proxy,
     route::Route,
+    settings::{ServerProfile, Settings},
 };
 #[derive(Debug, Store, Clone)]
 struct AppState {
     packet_builder: PacketBuilder,
+    /// Read once when the app starts, and saved with [`save_settings`] whenever they change.
+    settings: Signal<Settings>,
     username: Signal<String>,
     address: Signal<String>,
+    /// Proxy to connect through, from the login screen.
+    proxy: Signal<Option<Proxy>>,
+    /// The rest of what the login screen was given, the profile's name stays empty when it isn't
+    /// saved.
+    profile: Signal<ServerProfile>,
+    /// Whether the login screen should go on to the last profile by itself, only when the app
+    /// starts.
+    auto_connect: Signal<bool>,
     connection_notification: Signal<String>,
-    channels: Signal<Vec<String>>,
+    channels: Signal<Vec<ChannelInfo>>,
     packet_sender: Signal<Option<Sender<Packet>>>,
+    session: Signal<Option<ChatSession>>,
+    reconnect_status: Signal<Option<ReconnectStatus>>,
+    /// Ids of sent messages that are still waiting in the outbox.
+    pending_messages: Signal<HashSet<Uuid>>,
 }
 
 impl AppState {
@@ -33,19 +57,54 @@ impl AppState {
     }
 
     pub fn new() -> AppState {
-        let username = String::from("");
+        let settings = load_settings();
+        // the last profile fills in the login screen
+        let profile = settings.last_profile().cloned().unwrap_or_default();
+        let username = profile.nickname.clone();
+        let address = match profile.address.as_str() {
+            "" => String::from("127.0.0.1:10000"),
+            address => address.to_string(),
+        };
+        let proxy = match settings.last_profile() {
+            Some(profile) => profile.proxy.clone(),
+            None => settings.proxy.clone(),
+        };
+        // the proxy password isn't saved, it has to be typed in first
+        let auto_connect = settings.auto_connect
+            && settings.last_profile().is_some()
+            && !proxy.as_ref().is_some_and(Proxy::needs_password);
         let packet_builder = PacketBuilder::new(username.clone());
         AppState {
             packet_builder,
+            settings: Signal::new(settings),
             username: Signal::new(username),
-            address: Signal::new(String::from("127.0.0.1:10000")),
+            address: Signal::new(address),
+            proxy: Signal::new(proxy),
+            profile: Signal::new(profile),
+            auto_connect: Signal::new(auto_connect),
             connection_notification: Signal::new(String::from("")),
             channels: Signal::new(vec![]),
             packet_sender: Signal::new(None),
+            session: Signal::new(None),
+            reconnect_status: Signal::new(None),
+            pending_messages: Signal::new(HashSet::new()),
         }
     }
 }
 
+fn load_settings() -> Settings {
+    Settings::load(Settings::default_path()).unwrap_or_else(|err| {
+        println!("failed to load the settings, using the defaults: {}", err);
+        Settings::default()
+    })
+}
+
+fn save_settings(settings: &Settings) {
+    if let Err(err) = settings.save(Settings::default_path()) {
+        println!("failed to save the settings: {}", err);
+    }
+}
+
 static RESET_CSS: Asset = asset!("/assets/reset.css");
 static MAIN_CSS: Asset = asset!("/assets/main.css");
 
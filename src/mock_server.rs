@@ -0,0 +1,245 @@
+//! An in-process stand-in for the O4 chat server, for tests.
+//!
+//! Runs the protocol of [`crate::chat_server`] on an ephemeral port, or over in-memory
+//! connections made with [`MockServer::connect_in_memory`]. Errors, malformed lines and dropped
+//! connections can be injected with the methods of [`MockServer`].
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use smol::Task;
+use smol::net::TcpListener;
+
+use crate::chat_server::{Hub, Outgoing, ServerState, serve_client, spawn_accept_loop};
+use crate::packet::{ExtraFields, Packet};
+use crate::tcp_chat_client::TcpChatClient;
+use crate::transport::{SharedTransport, Transport, duplex};
+
+struct MockState {
+    server: ServerState,
+    /// Errors to answer the next requests with, instead of handling them.
+    queued_errors: VecDeque<(String, bool)>,
+    /// Requests are received but never answered, like from a peer that's gone.
+    silent: bool,
+    received: Vec<Packet>,
+}
+
+impl Hub for MockState {
+    fn state(&mut self) -> &mut ServerState {
+        &mut self.server
+    }
+
+    fn handle(&mut self, client_id: usize, packet: Packet) {
+        self.received.push(packet.clone());
+        if self.silent {
+            return;
+        }
+
+        match self.queued_errors.pop_front() {
+            Some((error, clientshutdown)) => {
+                self.server.send_error(client_id, &error, clientshutdown)
+            }
+            None => self.server.handle(client_id, packet),
+        }
+    }
+}
+
+/// A running mock server. Stops when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    _accept_task: Task<()>,
+    /// Clients served over transports handed to [`MockServer::serve`].
+    client_tasks: Mutex<Vec<Task<()>>>,
+}
+
+impl MockServer {
+    /// Starts a server with a single channel called `main`.
+    pub async fn start() -> io::Result<MockServer> {
+        MockServer::start_with_channels(&[("main", "Welcome to the mock server")]).await
+    }
+
+    /// Starts a server with the given `(name, topic)` channels. New clients join the first one.
+    pub async fn start_with_channels(channels: &[(&str, &str)]) -> io::Result<MockServer> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState {
+            server: ServerState::new(channels),
+            queued_errors: VecDeque::new(),
+            silent: false,
+            received: vec![],
+        }));
+        let accept_task = spawn_accept_loop(listener, state.clone());
+
+        Ok(MockServer {
+            addr,
+            state,
+            _accept_task: accept_task,
+            client_tasks: Mutex::new(vec![]),
+        })
+    }
+
+    /// The `host:port` the server listens on.
+    pub fn address(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// Serves a client over an already open connection, like one accepted from a Unix socket.
+    pub fn serve(&self, transport: impl Transport) {
+        let task = smol::spawn(serve_client(
+            SharedTransport::new(transport),
+            self.state.clone(),
+        ));
+        let mut tasks = self.client_tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    /// A client connected to the server through memory instead of a socket.
+    pub fn connect_in_memory(&self) -> TcpChatClient {
+        let (client, server) = duplex();
+        self.serve(server);
+        TcpChatClient::from_transport(client)
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.state.lock().unwrap().server.client_count()
+    }
+
+    /// Every packet the server has received so far.
+    pub fn received(&self) -> Vec<Packet> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Sends a packet to every connected client.
+    pub fn send_to_all(&self, packet: Packet) {
+        let state = self.state.lock().unwrap();
+        state
+            .server
+            .send_to_all(|| Outgoing::Packet(packet.clone()));
+    }
+
+    /// Sends an error packet to every connected client. With `clientshutdown` the clients are
+    /// disconnected afterwards.
+    pub fn inject_error(&self, error: &str, clientshutdown: bool) {
+        self.send_to_all(Packet::Error {
+            error: error.to_string(),
+            clientshutdown,
+            extra: ExtraFields::new(),
+        });
+        if clientshutdown {
+            self.disconnect_all();
+        }
+    }
+
+    /// Answers the next request from any client with an error packet instead of handling it.
+    pub fn fail_next(&self, error: &str, clientshutdown: bool) {
+        self.state
+            .lock()
+            .unwrap()
+            .queued_errors
+            .push_back((error.to_string(), clientshutdown));
+    }
+
+    /// Stops answering requests, while keeping the connections open.
+    pub fn go_silent(&self) {
+        self.state.lock().unwrap().silent = true;
+    }
+
+    /// Sends `line` to every connected client as is, e.g. to test malformed input.
+    pub fn send_raw(&self, line: &str) {
+        let state = self.state.lock().unwrap();
+        state.server.send_to_all(|| Outgoing::Raw(line.to_string()));
+    }
+
+    /// Closes every client connection, once everything sent before has been written.
+    pub fn disconnect_all(&self) {
+        let state = self.state.lock().unwrap();
+        state.server.send_to_all(|| Outgoing::Close);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet_builder::PacketBuilder;
+    use crate::tcp_chat_client::TcpChatClient;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_welcomes_clients() {
+        let server = MockServer::start().await.unwrap();
+        let mut client = TcpChatClient::connect(Some(&server.address()))
+            .await
+            .unwrap();
+
+        match client.recv().await.unwrap() {
+            Packet::Status { status, .. } => assert_eq!(status, "You joined the channel main"),
+            _ => panic!("should be a status packet"),
+        }
+        match client.recv().await.unwrap() {
+            Packet::ChangeTopic { topic, .. } => assert_eq!(topic, "Welcome to the mock server"),
+            _ => panic!("should be a topic packet"),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_answers_with_queued_errors() {
+        let server = MockServer::start().await.unwrap();
+        let builder = PacketBuilder::new("test user".into());
+        let mut client = TcpChatClient::connect(Some(&server.address()))
+            .await
+            .unwrap();
+        client.recv().await.unwrap();
+        client.recv().await.unwrap();
+
+        server.fail_next("nope", false);
+        client.send(builder.list_channels()).await.unwrap();
+        match client.recv().await.unwrap() {
+            Packet::Error {
+                error,
+                clientshutdown,
+                ..
+            } => {
+                assert_eq!(error, "nope");
+                assert!(!clientshutdown);
+            }
+            _ => panic!("should be an error packet"),
+        }
+
+        // the error is used up
+        client.send(builder.list_channels()).await.unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::ListChannels { .. }
+        ));
+        assert_eq!(server.received().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_serves_in_memory_clients() {
+        let server = MockServer::start_with_channels(&[("main", ""), ("dogs", "")])
+            .await
+            .unwrap();
+        let builder = PacketBuilder::new("test user".into());
+        let mut client = server.connect_in_memory();
+        client.recv().await.unwrap();
+        client.recv().await.unwrap();
+        assert_eq!(server.client_count(), 1);
+
+        client
+            .send(builder.join_channel("dogs".into()))
+            .await
+            .unwrap();
+        match client.recv().await.unwrap() {
+            Packet::Status { status, .. } => assert_eq!(status, "You joined the channel dogs"),
+            _ => panic!("should be a status packet"),
+        }
+        client.recv().await.unwrap();
+
+        server.disconnect_all();
+        assert!(client.recv().await.is_err());
+    }
+}
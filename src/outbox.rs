@@ -0,0 +1,197 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::packet::ChatMessage;
+
+/// A chat message waiting to be sent, with the channel it was written in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutboxEntry {
+    pub channel: String,
+    pub message: ChatMessage,
+}
+
+impl OutboxEntry {
+    /// Direct messages don't belong to a channel and can go out from anywhere. So can messages
+    /// written before the server had put us in any channel.
+    pub fn can_send_from(&self, channel: &str) -> bool {
+        self.message.directMessageTo.is_some() || self.channel.is_empty() || self.channel == channel
+    }
+}
+
+/// Chat messages that haven't made it to the server yet, in the order they were written.
+///
+/// With a path, the outbox is saved as JSON lines after every change so that nothing typed is
+/// lost when the app closes while offline.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    path: Option<PathBuf>,
+    entries: Vec<OutboxEntry>,
+}
+
+impl Outbox {
+    /// An outbox that only lives as long as the session.
+    pub fn in_memory() -> Outbox {
+        Outbox::default()
+    }
+
+    /// Loads the outbox saved at `path`, or starts an empty one there. Lines that can't be read
+    /// are skipped.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Outbox> {
+        let path = path.into();
+        let mut entries = vec![];
+        match fs::File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str(&line) {
+                        Ok(entry) => entries.push(entry),
+                        Err(err) => println!("Outbox: skipping unreadable entry: {err}"),
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(Outbox {
+            path: Some(path),
+            entries,
+        })
+    }
+
+    /// Where the outbox for the server at `addr` is kept, under [`crate::data_dir`].
+    pub fn path_for(addr: &str) -> PathBuf {
+        let name: String = addr
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        crate::data_dir()
+            .join("outbox")
+            .join(format!("{name}.jsonl"))
+    }
+
+    pub fn entries(&self) -> &[OutboxEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.entries.iter().any(|entry| &entry.message.id == id)
+    }
+
+    /// Adds a message to the end of the queue. A message that's already queued isn't added twice.
+    pub fn push(&mut self, channel: String, message: ChatMessage) -> io::Result<()> {
+        if self.contains(&message.id) {
+            return Ok(());
+        }
+        self.entries.push(OutboxEntry { channel, message });
+        self.save()
+    }
+
+    pub fn remove(&mut self, id: &Uuid) -> io::Result<Option<OutboxEntry>> {
+        let Some(index) = self.entries.iter().position(|e| &e.message.id == id) else {
+            return Ok(None);
+        };
+        let entry = self.entries.remove(index);
+        self.save()?;
+        Ok(Some(entry))
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        crate::write_atomically(path, |file| {
+            for entry in &self.entries {
+                serde_json::to_writer(&mut *file, entry)?;
+                file.write_all(b"\n")?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::Packet;
+    use crate::packet_builder::PacketBuilder;
+
+    use super::*;
+
+    fn message(text: &str) -> ChatMessage {
+        match PacketBuilder::new("test user".into()).chat_message(text.into()) {
+            Packet::Chat(message) => message,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn it_keeps_order_and_ids() {
+        let mut outbox = Outbox::in_memory();
+        let first = message("first");
+        let second = message("second");
+        outbox.push("main".into(), first.clone()).unwrap();
+        outbox.push("main".into(), second.clone()).unwrap();
+        outbox.push("main".into(), first.clone()).unwrap();
+        assert_eq!(outbox.entries().len(), 2);
+
+        assert!(outbox.remove(&first.id).unwrap().is_some());
+        assert!(!outbox.contains(&first.id));
+        assert_eq!(outbox.entries()[0].message, second);
+        assert!(outbox.remove(&first.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn it_survives_reopening() {
+        let path =
+            std::env::temp_dir().join(format!("neighbor_chat_outbox_{}.jsonl", Uuid::new_v4()));
+        let first = message("first");
+        let second = message("second");
+        {
+            let mut outbox = Outbox::open(&path).unwrap();
+            outbox.push("main".into(), first.clone()).unwrap();
+            outbox.push("dogs".into(), second.clone()).unwrap();
+        }
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.entries().len(), 2);
+        assert_eq!(outbox.entries()[0].message.message, "first");
+        assert_eq!(outbox.entries()[1].channel, "dogs");
+
+        outbox.remove(&first.id).unwrap();
+        assert_eq!(Outbox::open(&path).unwrap().entries().len(), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn direct_messages_go_out_anywhere() {
+        let mut dm = message("psst");
+        dm.directMessageTo = Some("bob".into());
+        let dm = OutboxEntry {
+            channel: "main".into(),
+            message: dm,
+        };
+        let chat = OutboxEntry {
+            channel: "main".into(),
+            message: message("hi"),
+        };
+        assert!(dm.can_send_from("dogs"));
+        assert!(chat.can_send_from("main"));
+        assert!(!chat.can_send_from("dogs"));
+    }
+}
@@ -1,12 +1,16 @@
-use std::str::FromStr;
+use std::fmt;
 
 use chrono::{DateTime, Utc};
-use serde_json::{Value, json};
-use serde_with::serde_as;
+use serde::de::Error as DeError;
+use serde::ser::Error as SerError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value, json};
+use serde_with::formats::Flexible;
+use serde_with::{BoolFromInt, serde_as};
 use uuid::Uuid;
 
 #[allow(non_snake_case)]
-#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct ChatMessage {
     pub id: Uuid,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -17,6 +21,9 @@ pub struct ChatMessage {
     pub directMessageTo: Option<String>,
     // timestamp in milliseconds
     pub sent: i64,
+    /// Fields this client doesn't know about, kept so they survive being sent onwards.
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 impl ChatMessage {
     pub fn datetime(&self) -> Option<DateTime<Utc>> {
@@ -29,85 +36,217 @@ impl PartialEq<ChatMessage> for ChatMessage {
     }
 }
 
+/// Unrecognized fields of a packet.
+pub type ExtraFields = Map<String, Value>;
+
+/// A single newline delimited json message of the O4 chat protocol.
+///
+/// The variant names are renamed to the integer `type` tag they have on the wire. The derived
+/// (externally tagged) impls are only used through the wire format, see the [`Serialize`] and
+/// [`Deserialize`] impls below, which move the tag into the `type` field.
 #[allow(non_snake_case)]
-#[derive(serde::Serialize, serde::Deserialize)]
-#[serde(untagged)]
 #[serde_as]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(remote = "Self")]
 pub enum Packet {
+    #[serde(rename = "-1")]
     Error {
         error: String,
-        #[serde_as(as = "BoolFromInt")]
+        /// Sent as `1` or `0`, any other integer counts as `true`.
+        #[serde_as(as = "BoolFromInt<Flexible>")]
         clientshutdown: bool,
+        #[serde(flatten)]
+        extra: ExtraFields,
     },
+    #[serde(rename = "0")]
     Status {
         status: String,
+        #[serde(flatten)]
+        extra: ExtraFields,
     },
+    #[serde(rename = "1")]
     Chat(ChatMessage),
+    #[serde(rename = "2")]
     JoinChannel {
         channel: String,
+        #[serde(flatten)]
+        extra: ExtraFields,
     },
+    #[serde(rename = "3")]
     ChangeTopic {
         topic: String,
+        #[serde(flatten)]
+        extra: ExtraFields,
     },
+    #[serde(rename = "4")]
     ListChannels {
         #[serde(skip_serializing_if = "Option::is_none")]
         channels: Option<Vec<String>>,
+        #[serde(flatten)]
+        extra: ExtraFields,
     },
+    /// A packet type this client doesn't understand. `raw` is the packet exactly as it was
+    /// received, `type` field included.
+    #[serde(skip)]
+    Unknown { kind: i64, raw: Value },
+}
+
+impl Serialize for Packet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_value()
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Packet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Packet, D::Error> {
+        Packet::from_value(Value::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
 }
 
 impl Packet {
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut data = serde_json::to_value(self).expect("coudln't convert packet into json");
-
-        data["type"] = match self {
-            Packet::Error { .. } => json!(-1),
-            Packet::Status { .. } => json!(0),
-            Packet::Chat { .. } => json!(1),
-            Packet::JoinChannel { .. } => json!(2),
-            Packet::ChangeTopic { .. } => json!(3),
-            Packet::ListChannels { .. } => json!(4),
+        serde_json::to_vec(self).expect("coudln't convert packet into json")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Packet, PacketError> {
+        let val: Value = serde_json::from_slice(bytes).map_err(PacketError::InvalidJson)?;
+        Packet::from_value(val)
+    }
+
+    /// Converts the packet into its wire representation.
+    pub fn to_value(&self) -> Result<Value, serde_json::Error> {
+        if let Packet::Unknown { raw, .. } = self {
+            return Ok(raw.clone());
+        }
+
+        // the derived impl produces {"<type>": {..fields}}
+        let tagged = Packet::serialize(self, serde_json::value::Serializer)?;
+        let Some((kind, fields)) = tagged.as_object().and_then(|obj| obj.iter().next()) else {
+            return Err(<serde_json::Error as SerError>::custom(
+                "packet didn't serialize into a tagged map",
+            ));
         };
+        let kind: i64 = kind
+            .parse()
+            .map_err(<serde_json::Error as SerError>::custom)?;
 
-        format!("{data}").into_bytes()
+        let mut data = match fields {
+            Value::Object(fields) => fields.clone(),
+            _ => Map::new(),
+        };
+        data.insert(String::from("type"), json!(kind));
+        Ok(Value::Object(data))
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Packet {
-        let val: Value =
-            serde_json::from_slice(bytes).expect("Unable to parse incoming data as json");
-        let t = val["type"].as_i64().unwrap();
-        match t {
-            -1 => Packet::Error {
-                error: val["error"].as_str().unwrap().into(),
-                clientshutdown: val["clientshutdown"].as_i64().unwrap() == 1i64,
-            },
-            0 => Packet::Status {
-                status: val["status"].as_str().unwrap().into(),
-            },
-            1 => Packet::Chat(ChatMessage {
-                id: Uuid::from_str(val["id"].as_str().unwrap())
-                    .expect("couldn't deserialize id as UUID"),
-                inReplyTo: val["inReplyTo"]
-                    .as_str()
-                    .map(|r| Uuid::from_str(r).expect("couldn't deserialize id as UUID")),
-                message: val["message"].as_str().unwrap().into(),
-                user: val["user"].as_str().unwrap().into(),
-                directMessageTo: val["directMessageTo"].as_str().map(|s| s.to_string()),
-                sent: val["sent"].as_i64().unwrap(),
-            }),
-            2 => Packet::JoinChannel {
-                channel: val["channel"].as_str().unwrap().into(),
-            },
-            3 => Packet::ChangeTopic {
-                topic: val["topic"].as_str().unwrap().into(),
-            },
-            4 => Packet::ListChannels {
-                channels: val["channels"].as_array().map(|arr| {
-                    arr.iter()
-                        .map(|v| v.as_str().unwrap().to_string())
-                        .collect()
-                }),
-            },
-            _ => panic!("unknown Packet type {t}"),
+    /// Decodes a packet from its wire representation.
+    pub fn from_value(val: Value) -> Result<Packet, PacketError> {
+        let Some(obj) = val.as_object() else {
+            return Err(PacketError::MissingType);
+        };
+        let kind = match obj.get("type") {
+            None | Some(Value::Null) => return Err(PacketError::MissingType),
+            Some(t) => t.as_i64().ok_or_else(|| PacketError::InvalidField {
+                field: String::from("type"),
+                source: <serde_json::Error as DeError>::custom("expected an integer"),
+            })?,
+        };
+
+        let Some((_, required)) = KNOWN_TYPES.iter().find(|(known, _)| *known == kind) else {
+            return Ok(Packet::Unknown { kind, raw: val });
+        };
+        if let Some(missing) = required.iter().find(|field| !obj.contains_key(**field)) {
+            return Err(PacketError::MissingField(missing.to_string()));
+        }
+
+        let mut fields = obj.clone();
+        fields.remove("type");
+        let tagged = json!({ kind.to_string(): Value::Object(fields) });
+
+        // calls the derived impl, tracking which field failed
+        let mut track = serde_path_to_error::Track::new();
+        match Packet::deserialize(serde_path_to_error::Deserializer::new(tagged, &mut track)) {
+            Ok(packet) => Ok(packet),
+            Err(source) => {
+                let field = track
+                    .path()
+                    .iter()
+                    .skip(1) // the type tag
+                    .map(|segment| segment.to_string())
+                    .collect::<Vec<String>>()
+                    .join(".");
+                if UUID_FIELDS.contains(&field.as_str())
+                    && let Some(Err(uuid_err)) = obj
+                        .get(&field)
+                        .and_then(|v| v.as_str())
+                        .map(Uuid::parse_str)
+                {
+                    return Err(PacketError::InvalidUuid {
+                        field,
+                        source: uuid_err,
+                    });
+                }
+                Err(PacketError::InvalidField { field, source })
+            }
+        }
+    }
+}
+
+/// The `type` tags of [`Packet`]'s variants, with the fields a packet of that type can't do
+/// without.
+const KNOWN_TYPES: [(i64, &[&str]); 6] = [
+    (-1, &["error", "clientshutdown"]),
+    (0, &["status"]),
+    (1, &["id", "message", "user", "sent"]),
+    (2, &["channel"]),
+    (3, &["topic"]),
+    (4, &[]),
+];
+
+/// Fields of [`ChatMessage`] that contain a UUID.
+const UUID_FIELDS: [&str; 2] = ["id", "inReplyTo"];
+
+/// Reasons why incoming bytes couldn't be decoded into a [`Packet`].
+#[derive(Debug)]
+pub enum PacketError {
+    InvalidJson(serde_json::Error),
+    MissingType,
+    MissingField(String),
+    /// The field exists but has the wrong JSON type or value.
+    InvalidField {
+        field: String,
+        source: serde_json::Error,
+    },
+    InvalidUuid {
+        field: String,
+        source: uuid::Error,
+    },
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::InvalidJson(err) => write!(f, "packet is not valid json: {err}"),
+            PacketError::MissingType => write!(f, "packet is missing the \"type\" field"),
+            PacketError::MissingField(field) => write!(f, "packet is missing field \"{field}\""),
+            PacketError::InvalidField { field, source } => {
+                write!(f, "packet field \"{field}\" is invalid: {source}")
+            }
+            PacketError::InvalidUuid { field, source } => {
+                write!(f, "packet field \"{field}\" is not a valid UUID: {source}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PacketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PacketError::InvalidJson(err) => Some(err),
+            PacketError::InvalidField { source, .. } => Some(source),
+            PacketError::InvalidUuid { source, .. } => Some(source),
+            _ => None,
         }
     }
 }
@@ -126,11 +265,12 @@ mod tests {
             "error" : "Some error message here",
             "clientshutdown": 0
         }"#;
-        let packet = Packet::from_bytes(val.as_bytes());
+        let packet = Packet::from_bytes(val.as_bytes()).unwrap();
         match packet {
             Packet::Error {
                 error,
                 clientshutdown,
+                ..
             } => {
                 assert_eq!(error, "Some error message here");
                 assert!(!clientshutdown);
@@ -144,9 +284,9 @@ mod tests {
             "type": 0,
             "status" : "Some status message here"
         }"#;
-        let packet = Packet::from_bytes(val.as_bytes());
+        let packet = Packet::from_bytes(val.as_bytes()).unwrap();
         match packet {
-            Packet::Status { status } => {
+            Packet::Status { status, .. } => {
                 assert_eq!(status, "Some status message here");
             }
             _ => panic!("should be a status packet"),
@@ -162,7 +302,7 @@ mod tests {
             "user" : "telemakos", 
             "sent":16782697319
         }"#;
-        let packet = Packet::from_bytes(val.as_bytes());
+        let packet = Packet::from_bytes(val.as_bytes()).unwrap();
         match packet {
             Packet::Chat(chat) => {
                 assert_eq!(
@@ -193,7 +333,7 @@ mod tests {
             "user" : "telemakos", 
             "sent": 16782697219
         }"#;
-        let packet = Packet::from_bytes(val.as_bytes());
+        let packet = Packet::from_bytes(val.as_bytes()).unwrap();
         match packet {
             Packet::Chat(chat) => {
                 assert_eq!(
@@ -224,6 +364,7 @@ mod tests {
             user: String::from("test user"),
             directMessageTo: None,
             sent: 1770656066123,
+            extra: ExtraFields::new(),
         });
 
         let parts: Vec<String> = vec![
@@ -259,12 +400,13 @@ mod tests {
                     .as_millis(),
             )
             .expect("timestamp doesn't fint i64"),
+            extra: ExtraFields::new(),
         });
 
         let bytes = original.to_bytes();
         let str = String::from_utf8(bytes.clone()).unwrap();
         println!("Data: {str}");
-        let packet = Packet::from_bytes(&bytes);
+        let packet = Packet::from_bytes(&bytes).unwrap();
 
         match packet {
             Packet::Chat(p) => match original {
@@ -281,4 +423,268 @@ mod tests {
             _ => panic!("should be a chat packet"),
         }
     }
+
+    #[test]
+    fn invalid_json_is_an_error() {
+        let packet = Packet::from_bytes(b"{\"type\": 0, \"status\": ");
+        assert!(matches!(packet, Err(PacketError::InvalidJson(_))));
+    }
+
+    #[test]
+    fn missing_type_is_an_error() {
+        let packet = Packet::from_bytes(br#"{"status": "hello"}"#);
+        assert!(matches!(packet, Err(PacketError::MissingType)));
+    }
+
+    #[test]
+    fn unknown_type_round_trips() {
+        let val = r#"{"type":42,"poll":{"question":"pizza?","options":["yes","no"]}}"#;
+        let packet = Packet::from_bytes(val.as_bytes()).unwrap();
+        match &packet {
+            Packet::Unknown { kind, raw } => {
+                assert_eq!(*kind, 42);
+                assert_eq!(raw["poll"]["question"], "pizza?");
+            }
+            _ => panic!("should be an unknown packet"),
+        }
+
+        let bytes = packet.to_bytes();
+        let original: Value = serde_json::from_str(val).unwrap();
+        let reserialized: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(original, reserialized);
+    }
+
+    #[test]
+    fn extra_fields_are_kept() {
+        let val = r#"{
+            "type" : 1,
+            "id" : "d4986bec-8026-462a-9a7a-f04eebcf7612",
+            "message" : "hello",
+            "user" : "telemakos",
+            "sent": 16782697219,
+            "edited": true
+        }"#;
+        let packet = Packet::from_bytes(val.as_bytes()).unwrap();
+        match &packet {
+            Packet::Chat(chat) => {
+                assert_eq!(chat.extra.get("edited"), Some(&json!(true)));
+                assert!(!chat.extra.contains_key("type"));
+            }
+            _ => panic!("should be a chat packet"),
+        }
+
+        let original: Value = serde_json::from_str(val).unwrap();
+        let reserialized: Value = serde_json::from_slice(&packet.to_bytes()).unwrap();
+        assert_eq!(original, reserialized);
+
+        let packet = Packet::from_bytes(br#"{"type":0,"status":"hi","color":"red"}"#).unwrap();
+        match packet {
+            Packet::Status { status, extra } => {
+                assert_eq!(status, "hi");
+                assert_eq!(extra.get("color"), Some(&json!("red")));
+            }
+            _ => panic!("should be a status packet"),
+        }
+    }
+
+    #[test]
+    fn missing_field_is_an_error() {
+        let packet = Packet::from_bytes(br#"{"type": 0}"#);
+        assert!(matches!(packet, Err(PacketError::MissingField(f)) if f == "status"));
+    }
+
+    #[test]
+    fn every_required_field_is_checked() {
+        let packets = [
+            json!({"type": -1, "error": "bye", "clientshutdown": 0}),
+            json!({"type": 0, "status": "hi"}),
+            json!({
+                "type": 1,
+                "id": "d4986bec-8026-462a-9a7a-f04eebcf7612",
+                "message": "hello",
+                "user": "telemakos",
+                "sent": 16782697219i64
+            }),
+            json!({"type": 2, "channel": "main"}),
+            json!({"type": 3, "topic": "cats"}),
+            json!({"type": 4}),
+        ];
+        for (packet, (kind, required)) in packets.iter().zip(KNOWN_TYPES) {
+            assert_eq!(packet["type"], kind);
+            assert!(Packet::from_value(packet.clone()).is_ok());
+            for field in required {
+                let mut without = packet.clone();
+                without.as_object_mut().unwrap().remove(*field);
+                assert!(matches!(
+                    Packet::from_value(without),
+                    Err(PacketError::MissingField(f)) if f == *field
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn mistyped_field_is_an_error() {
+        let packet = Packet::from_bytes(br#"{"type": 3, "topic": 5}"#);
+        assert!(matches!(packet, Err(PacketError::InvalidField { field, .. }) if field == "topic"));
+
+        let packet = Packet::from_bytes(br#"{"type": 4, "channels": ["main", 1]}"#);
+        assert!(matches!(
+            packet,
+            Err(PacketError::InvalidField { field, .. }) if field.starts_with("channels")
+        ));
+    }
+
+    #[test]
+    fn bad_uuid_is_an_error() {
+        let val = r#"{
+            "type" : 1,
+            "id" : "not-a-uuid",
+            "message" : "hello",
+            "user" : "telemakos",
+            "sent": 16782697219
+        }"#;
+        let packet = Packet::from_bytes(val.as_bytes());
+        assert!(matches!(
+            packet,
+            Err(PacketError::InvalidUuid { field, .. }) if field == "id"
+        ));
+    }
+
+    #[test]
+    fn error_serializes_clientshutdown_as_int() {
+        let packet = Packet::Error {
+            error: String::from("bye"),
+            clientshutdown: true,
+            extra: ExtraFields::new(),
+        };
+        let val: Value = serde_json::from_slice(&packet.to_bytes()).unwrap();
+        assert_eq!(
+            val,
+            json!({"type": -1, "error": "bye", "clientshutdown": 1})
+        );
+
+        match Packet::from_bytes(&packet.to_bytes()).unwrap() {
+            Packet::Error { clientshutdown, .. } => assert!(clientshutdown),
+            _ => panic!("should be an error packet"),
+        }
+    }
+
+    #[test]
+    fn any_nonzero_clientshutdown_is_true() {
+        let packet = Packet::from_bytes(br#"{"type": -1, "error": "bye", "clientshutdown": 2}"#);
+        assert!(matches!(
+            packet,
+            Ok(Packet::Error {
+                clientshutdown: true,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn list_channels_request_has_only_type() {
+        let packet = Packet::ListChannels {
+            channels: None,
+            extra: ExtraFields::new(),
+        };
+        let val: Value = serde_json::from_slice(&packet.to_bytes()).unwrap();
+        assert_eq!(val, json!({"type": 4}));
+    }
+
+    #[test]
+    fn packet_is_serde_compatible() {
+        let packet: Packet = serde_json::from_str(r#"{"type": 3, "topic": "hello"}"#).unwrap();
+        assert!(matches!(&packet, Packet::ChangeTopic { topic, .. } if topic == "hello"));
+        assert_eq!(
+            serde_json::to_value(&packet).unwrap(),
+            json!({"type": 3, "topic": "hello"})
+        );
+    }
+
+    mod round_trip {
+        use proptest::collection::{btree_map, vec};
+        use proptest::option;
+        use proptest::prelude::*;
+
+        use super::*;
+
+        fn extra_fields() -> impl Strategy<Value = ExtraFields> {
+            // prefixed so that they never collide with a known field
+            btree_map(
+                "x_[a-z]{1,8}",
+                prop_oneof![
+                    any::<bool>().prop_map(Value::from),
+                    any::<i64>().prop_map(Value::from),
+                    ".*".prop_map(Value::from),
+                ],
+                0..3,
+            )
+            .prop_map(|fields| fields.into_iter().collect())
+        }
+
+        fn uuid() -> impl Strategy<Value = Uuid> {
+            any::<u128>().prop_map(Uuid::from_u128)
+        }
+
+        fn chat_message() -> impl Strategy<Value = ChatMessage> {
+            (
+                uuid(),
+                option::of(uuid()),
+                ".*",
+                ".*",
+                option::of(".*"),
+                any::<i64>(),
+                extra_fields(),
+            )
+                .prop_map(|(id, reply, message, user, dm, sent, extra)| ChatMessage {
+                    id,
+                    inReplyTo: reply,
+                    message,
+                    user,
+                    directMessageTo: dm,
+                    sent,
+                    extra,
+                })
+        }
+
+        fn packet() -> impl Strategy<Value = Packet> {
+            prop_oneof![
+                (".*", any::<bool>(), extra_fields()).prop_map(|(error, clientshutdown, extra)| {
+                    Packet::Error {
+                        error,
+                        clientshutdown,
+                        extra,
+                    }
+                }),
+                (".*", extra_fields()).prop_map(|(status, extra)| Packet::Status { status, extra }),
+                chat_message().prop_map(Packet::Chat),
+                (".*", extra_fields())
+                    .prop_map(|(channel, extra)| Packet::JoinChannel { channel, extra }),
+                (".*", extra_fields())
+                    .prop_map(|(topic, extra)| Packet::ChangeTopic { topic, extra }),
+                (option::of(vec(".*", 0..5)), extra_fields())
+                    .prop_map(|(channels, extra)| Packet::ListChannels { channels, extra }),
+                (100i64..1000, extra_fields()).prop_map(|(kind, extra)| {
+                    let mut raw = extra;
+                    raw.insert(String::from("type"), json!(kind));
+                    Packet::Unknown {
+                        kind,
+                        raw: Value::Object(raw),
+                    }
+                }),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn packets_survive_a_round_trip(packet in packet()) {
+                let bytes = packet.to_bytes();
+                let decoded = Packet::from_bytes(&bytes).unwrap();
+
+                prop_assert_eq!(packet.to_value().unwrap(), decoded.to_value().unwrap());
+                prop_assert_eq!(bytes, decoded.to_bytes());
+            }
+        }
+    }
 }
@@ -3,7 +3,7 @@ use std::sync::{Arc, Mutex};
 use chrono::Utc;
 use uuid::Uuid;
 
-use crate::packet::{ChatMessage, Packet};
+use crate::packet::{ChatMessage, ExtraFields, Packet};
 
 #[derive(Debug)]
 pub struct PacketBuilder {
@@ -45,18 +45,105 @@ impl PacketBuilder {
             user: self.get_nickname(),
             directMessageTo: None,
             sent: timestamp,
+            extra: ExtraFields::new(),
         })
     }
+
+    /// A chat message only the users called `to` get, on whichever channel they are.
+    pub fn direct_message(&self, to: String, message: String) -> Packet {
+        let mut packet = self.chat_message(message);
+        if let Packet::Chat(message) = &mut packet {
+            message.directMessageTo = Some(to);
+        }
+        packet
+    }
+
+    /// A chat message answering `to`. Replies to a direct message are direct messages to the
+    /// other user in that conversation.
+    pub fn reply(&self, to: &ChatMessage, message: String) -> Packet {
+        let mut packet = match &to.directMessageTo {
+            Some(recipient) if to.user == self.get_nickname() => {
+                self.direct_message(recipient.clone(), message)
+            }
+            Some(_) => self.direct_message(to.user.clone(), message),
+            None => self.chat_message(message),
+        };
+        if let Packet::Chat(message) = &mut packet {
+            message.inReplyTo = Some(to.id);
+        }
+        packet
+    }
+
     pub fn set_topic(&self, new_topic: String) -> Packet {
-        Packet::ChangeTopic { topic: new_topic }
+        Packet::ChangeTopic {
+            topic: new_topic,
+            extra: ExtraFields::new(),
+        }
     }
 
-    #[allow(unused)]
     pub fn list_channels(&self) -> Packet {
-        Packet::ListChannels { channels: None }
+        Packet::ListChannels {
+            channels: None,
+            extra: ExtraFields::new(),
+        }
     }
-    #[allow(unused)]
     pub fn join_channel(&self, channel: String) -> Packet {
-        Packet::JoinChannel { channel }
+        Packet::JoinChannel {
+            channel,
+            extra: ExtraFields::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(packet: Packet) -> ChatMessage {
+        match packet {
+            Packet::Chat(message) => message,
+            _ => panic!("should be a chat message"),
+        }
+    }
+
+    #[test]
+    fn replies_point_at_their_parent() {
+        let alice = PacketBuilder::new("alice".into());
+        let bob = PacketBuilder::new("bob".into());
+        let parent = chat(alice.chat_message("hello".into()));
+
+        let reply = chat(bob.reply(&parent, "hi".into()));
+        assert_eq!(reply.inReplyTo, Some(parent.id));
+        assert_eq!(reply.directMessageTo, None);
+        assert_eq!(reply.user, "bob");
+        assert_eq!(reply.message, "hi");
+    }
+
+    #[test]
+    fn direct_messages_name_their_recipient() {
+        let alice = PacketBuilder::new("alice".into());
+        let packet = alice.direct_message("bob".into(), "psst".into());
+
+        let read_back = chat(Packet::from_bytes(&packet.to_bytes()).unwrap());
+        let message = chat(packet);
+        assert_eq!(message.directMessageTo.as_deref(), Some("bob"));
+        assert_eq!(message.user, "alice");
+        assert_eq!(read_back, message);
+        assert_eq!(read_back.directMessageTo.as_deref(), Some("bob"));
+        assert_eq!(read_back.message, "psst");
+    }
+
+    #[test]
+    fn replies_to_direct_messages_stay_in_the_conversation() {
+        let alice = PacketBuilder::new("alice".into());
+        let bob = PacketBuilder::new("bob".into());
+        let parent = chat(alice.direct_message("bob".into(), "psst".into()));
+
+        let answer = chat(bob.reply(&parent, "what".into()));
+        assert_eq!(answer.directMessageTo.as_deref(), Some("alice"));
+        assert_eq!(answer.inReplyTo, Some(parent.id));
+
+        let follow_up = chat(alice.reply(&parent, "nothing".into()));
+        assert_eq!(follow_up.directMessageTo.as_deref(), Some("bob"));
     }
 }
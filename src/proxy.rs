@@ -0,0 +1,583 @@
+//! Reaching the server through a SOCKS5 or HTTP CONNECT proxy.
+//!
+//! Proxies are written as URLs, `socks5://[user:password@]host[:port]` or
+//! `http://[user:password@]host[:port]`. Host names are resolved by the proxy, not by us, since
+//! behind a proxy the chat server often can't be looked up directly.
+
+use std::fmt;
+#[cfg(not(target_arch = "wasm32"))]
+use std::io;
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::IpAddr;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+use base64::Engine;
+#[cfg(not(target_arch = "wasm32"))]
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+#[cfg(not(target_arch = "wasm32"))]
+use smol::Timer;
+#[cfg(not(target_arch = "wasm32"))]
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(not(target_arch = "wasm32"))]
+use smol::net::TcpStream;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::server_address::CONNECTION_ATTEMPT_TIMEOUT;
+use crate::server_address::{ConnectError, ServerAddress};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+impl ProxyKind {
+    fn scheme(self) -> &'static str {
+        match self {
+            ProxyKind::Socks5 => "socks5",
+            ProxyKind::Http => "http",
+        }
+    }
+
+    fn default_port(self) -> u16 {
+        match self {
+            ProxyKind::Socks5 => 1080,
+            ProxyKind::Http => 8080,
+        }
+    }
+}
+
+/// A proxy to connect to the server through.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    /// `host:port` of the proxy.
+    pub address: String,
+    pub username: Option<String>,
+    /// Only kept for the session, it's asked for again on the login screen.
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+}
+
+/// Shows the proxy as the URL it can be parsed from, without the password.
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://", self.kind.scheme())?;
+        if let Some(username) = &self.username {
+            write!(f, "{username}@")?;
+        }
+        write!(f, "{}", self.address)
+    }
+}
+
+impl Proxy {
+    /// Parses a proxy URL, see the [module docs](self).
+    pub fn parse(url: &str) -> Result<Proxy, ConnectError> {
+        let url = url.trim();
+        let invalid = |reason: &str| ConnectError::InvalidAddress {
+            address: url.to_string(),
+            reason: reason.to_string(),
+        };
+        let (kind, rest) = if let Some(rest) = url.strip_prefix("socks5://") {
+            (ProxyKind::Socks5, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (ProxyKind::Http, rest)
+        } else {
+            return Err(invalid("proxies start with socks5:// or http://"));
+        };
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+        let (credentials, host_port) = match rest.rsplit_once('@') {
+            Some((credentials, host_port)) => (Some(credentials), host_port),
+            None => (None, rest),
+        };
+        let (username, password) = match credentials {
+            None => (None, None),
+            Some(credentials) => match credentials.split_once(':') {
+                Some((username, password)) => {
+                    (Some(username.to_string()), Some(password.to_string()))
+                }
+                None => (Some(credentials.to_string()), None),
+            },
+        };
+        if username.as_ref().is_some_and(|name| name.is_empty()) {
+            return Err(invalid("the user name is missing before the :"));
+        }
+        if kind == ProxyKind::Socks5
+            && [&username, &password]
+                .iter()
+                .any(|value| value.as_ref().is_some_and(|value| value.len() > 255))
+        {
+            return Err(invalid(
+                "SOCKS5 user names and passwords are at most 255 bytes",
+            ));
+        }
+        let address = ServerAddress::parse_with_default_port(host_port, kind.default_port())
+            .map_err(|err| match err {
+                ConnectError::InvalidAddress { reason, .. } => invalid(&reason),
+                err => err,
+            })?;
+
+        Ok(Proxy {
+            kind,
+            address: address.to_string(),
+            username,
+            password,
+        })
+    }
+
+    /// Whether there's a user name but the password still has to be asked for.
+    pub fn needs_password(&self) -> bool {
+        self.username.is_some() && self.password.is_none()
+    }
+}
+
+// browsers don't let pages open sockets, let alone talk to proxies
+#[cfg(not(target_arch = "wasm32"))]
+impl Proxy {
+    /// Opens a TCP connection to `target` through the proxy.
+    pub async fn connect(&self, target: &ServerAddress) -> Result<TcpStream, ConnectError> {
+        self.connect_within(target, CONNECTION_ATTEMPT_TIMEOUT)
+            .await
+    }
+
+    /// Like [`connect`](Self::connect), failing if the proxy takes longer than `timeout` to
+    /// answer the handshake.
+    async fn connect_within(
+        &self,
+        target: &ServerAddress,
+        timeout: Duration,
+    ) -> Result<TcpStream, ConnectError> {
+        let proxy_address = ServerAddress::parse(&self.address)?;
+        let mut stream = proxy_address.connect().await?;
+        let handshake = smol::future::or(
+            async {
+                match self.kind {
+                    ProxyKind::Socks5 => self.socks5_handshake(&mut stream, target).await,
+                    ProxyKind::Http => self.http_handshake(&mut stream, target).await,
+                }
+            },
+            async {
+                Timer::after(timeout).await;
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "it didn't answer in time",
+                ))
+            },
+        )
+        .await;
+        handshake.map_err(|err| ConnectError::Proxy {
+            proxy: self.to_string(),
+            reason: err.to_string(),
+        })?;
+        Ok(stream)
+    }
+
+    async fn socks5_handshake(
+        &self,
+        stream: &mut TcpStream,
+        target: &ServerAddress,
+    ) -> io::Result<()> {
+        // no authentication, or username/password when we have one
+        let methods: &[u8] = match self.username {
+            Some(_) => &[0x00, 0x02],
+            None => &[0x00],
+        };
+        let mut greeting = vec![0x05, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        stream.write_all(&greeting).await?;
+
+        let mut choice = [0; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != 0x05 {
+            return Err(proxy_error("it isn't a SOCKS5 proxy"));
+        }
+        match choice[1] {
+            0x00 => {}
+            0x02 => {
+                let username = self.username.as_deref().unwrap_or_default().as_bytes();
+                let password = self.password.as_deref().unwrap_or_default().as_bytes();
+                let mut auth = vec![0x01, username.len() as u8];
+                auth.extend_from_slice(username);
+                auth.push(password.len() as u8);
+                auth.extend_from_slice(password);
+                stream.write_all(&auth).await?;
+
+                let mut status = [0; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0x00 {
+                    return Err(proxy_error("the user name or password was rejected"));
+                }
+            }
+            _ => {
+                return Err(proxy_error(match self.username {
+                    Some(_) => "none of our authentication methods are accepted",
+                    None => "it needs a user name and password",
+                }));
+            }
+        }
+
+        let mut request = vec![0x05, 0x01, 0x00];
+        match target.host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let host = target.host.as_bytes();
+                if host.len() > 255 {
+                    return Err(proxy_error("the host name is too long"));
+                }
+                request.push(0x03);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host);
+            }
+        }
+        request.extend_from_slice(&target.port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(proxy_error(match reply[1] {
+                0x02 => "the connection isn't allowed by its rules",
+                0x03 => "the network is unreachable from it",
+                0x04 => "the server is unreachable from it",
+                0x05 => "the server refused the connection",
+                0x06 => "the connection timed out",
+                0x07 | 0x08 => "it doesn't support the request",
+                _ => "it failed to connect",
+            }));
+        }
+        // the address the proxy connected from, which we don't need
+        let bound_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len).await?;
+                len[0] as usize
+            }
+            _ => return Err(proxy_error("its reply can't be read")),
+        };
+        let mut bound = vec![0; bound_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    async fn http_handshake(
+        &self,
+        stream: &mut TcpStream,
+        target: &ServerAddress,
+    ) -> io::Result<()> {
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some(username) = &self.username {
+            let credentials = format!("{}:{}", username, self.password.as_deref().unwrap_or(""));
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                BASE64.encode(credentials)
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // read a byte at a time, what comes after the headers is already from the server
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            if stream.read(&mut byte).await? == 0 {
+                return Err(proxy_error("it closed the connection"));
+            }
+            response.push(byte[0]);
+            if response.len() > 16 * 1024 {
+                return Err(proxy_error("its response is too long"));
+            }
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        if !status.starts_with('2') {
+            return Err(proxy_error(&format!("it answered \"{status_line}\"")));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn proxy_error(reason: &str) -> io::Error {
+    io::Error::other(reason.to_string())
+}
+
+/// Opens a TCP connection to `target`, through `proxy` if there is one.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn connect(
+    target: &ServerAddress,
+    proxy: Option<&Proxy>,
+) -> Result<TcpStream, ConnectError> {
+    match proxy {
+        Some(proxy) => proxy.connect(target).await,
+        None => target.connect().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::Task;
+    use smol::io::{AsyncBufReadExt, BufReader};
+    use smol::net::TcpListener;
+
+    use crate::mock_server::MockServer;
+    use crate::packet::Packet;
+    use crate::tcp_chat_client::TcpChatClient;
+
+    use super::*;
+
+    /// Copies both ways between the client and the server until either is done.
+    async fn relay(client: TcpStream, server: TcpStream) {
+        smol::future::race(
+            async {
+                let _ = smol::io::copy(client.clone(), server.clone()).await;
+            },
+            async {
+                let _ = smol::io::copy(server.clone(), client.clone()).await;
+            },
+        )
+        .await;
+    }
+
+    /// A SOCKS5 proxy for one client, that takes only `credentials` if given. Targets are
+    /// reached by name, so "localhost" works.
+    async fn socks5_proxy(credentials: Option<(&'static str, &'static str)>) -> (String, Task<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let task = smol::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut header = [0; 2];
+            client.read_exact(&mut header).await.unwrap();
+            let mut methods = vec![0; header[1] as usize];
+            client.read_exact(&mut methods).await.unwrap();
+
+            let wanted = if credentials.is_some() { 0x02 } else { 0x00 };
+            if !methods.contains(&wanted) {
+                client.write_all(&[0x05, 0xFF]).await.unwrap();
+                return;
+            }
+            client.write_all(&[0x05, wanted]).await.unwrap();
+            if let Some((username, password)) = credentials {
+                let mut len = [0; 2];
+                client.read_exact(&mut len).await.unwrap();
+                let mut got_username = vec![0; len[1] as usize];
+                client.read_exact(&mut got_username).await.unwrap();
+                client.read_exact(&mut len[..1]).await.unwrap();
+                let mut got_password = vec![0; len[0] as usize];
+                client.read_exact(&mut got_password).await.unwrap();
+                if got_username != username.as_bytes() || got_password != password.as_bytes() {
+                    client.write_all(&[0x01, 0x01]).await.unwrap();
+                    return;
+                }
+                client.write_all(&[0x01, 0x00]).await.unwrap();
+            }
+
+            let mut request = [0; 4];
+            client.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[3], 0x03, "host names should be sent as they are");
+            let mut len = [0; 1];
+            client.read_exact(&mut len).await.unwrap();
+            let mut host = vec![0; len[0] as usize];
+            client.read_exact(&mut host).await.unwrap();
+            let mut port = [0; 2];
+            client.read_exact(&mut port).await.unwrap();
+            let target = format!(
+                "{}:{}",
+                String::from_utf8(host).unwrap(),
+                u16::from_be_bytes(port)
+            );
+
+            let Ok(server) = TcpStream::connect(target).await else {
+                client
+                    .write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                return;
+            };
+            client
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 0])
+                .await
+                .unwrap();
+            relay(client, server).await;
+        });
+        (addr, task)
+    }
+
+    /// An HTTP proxy for one client, answering CONNECT requests.
+    async fn http_proxy(authorization: Option<&'static str>) -> (String, Task<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let task = smol::spawn(async move {
+            let (client, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(client.clone());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).await.unwrap();
+            let mut got_authorization = None;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).await.unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Proxy-Authorization: ") {
+                    got_authorization = Some(value.trim().to_string());
+                }
+            }
+
+            let mut client = client;
+            if got_authorization.as_deref() != authorization {
+                client
+                    .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                    .await
+                    .unwrap();
+                return;
+            }
+            let target = request_line.split_whitespace().nth(1).unwrap().to_string();
+            let server = TcpStream::connect(target).await.unwrap();
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            relay(client, server).await;
+        });
+        (addr, task)
+    }
+
+    /// The mock server's address by name, for the proxy to resolve.
+    fn by_name(server: &MockServer) -> String {
+        server.address().replace("127.0.0.1", "localhost")
+    }
+
+    async fn connect_through(server: &MockServer, proxy: &Proxy) -> io::Result<TcpChatClient> {
+        TcpChatClient::connect_via(Some(&by_name(server)), &Default::default(), Some(proxy)).await
+    }
+
+    #[test]
+    fn it_parses_proxy_urls() {
+        let proxy = Proxy::parse("socks5://alice:s3:cret@proxy.example.com").unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Socks5);
+        assert_eq!(proxy.address, "proxy.example.com:1080");
+        assert_eq!(proxy.username.as_deref(), Some("alice"));
+        assert_eq!(proxy.password.as_deref(), Some("s3:cret"));
+        assert_eq!(proxy.to_string(), "socks5://alice@proxy.example.com:1080");
+
+        let proxy = Proxy::parse("http://10.0.0.1:3128/").unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Http);
+        assert_eq!(proxy.address, "10.0.0.1:3128");
+        assert_eq!(proxy.username, None);
+
+        assert!(Proxy::parse("ftp://proxy").is_err());
+        assert!(Proxy::parse("socks5://:secret@proxy").is_err());
+        assert!(Proxy::parse("http://proxy:port").is_err());
+    }
+
+    #[tokio::test]
+    async fn it_connects_through_socks5() {
+        let server = MockServer::start().await.unwrap();
+        let (proxy_addr, _proxy) = socks5_proxy(None).await;
+        let proxy = Proxy::parse(&format!("socks5://{proxy_addr}")).unwrap();
+
+        let mut client = connect_through(&server, &proxy).await.unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Status { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn socks5_credentials_are_checked() {
+        let server = MockServer::start().await.unwrap();
+        let (proxy_addr, _proxy) = socks5_proxy(Some(("alice", "secret"))).await;
+        let proxy = Proxy::parse(&format!("socks5://alice:secret@{proxy_addr}")).unwrap();
+        let mut client = connect_through(&server, &proxy).await.unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Status { .. }
+        ));
+
+        let (proxy_addr, _proxy) = socks5_proxy(Some(("alice", "secret"))).await;
+        let proxy = Proxy::parse(&format!("socks5://alice:wrong@{proxy_addr}")).unwrap();
+        let err = connect_through(&server, &proxy).await.err().unwrap();
+        match ConnectError::from_io_error(&err) {
+            Some(ConnectError::Proxy { reason, .. }) => assert!(reason.contains("rejected")),
+            other => panic!("expected a proxy error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_failures_are_reported() {
+        // nothing listens on the port once the listener is dropped
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (proxy_addr, _proxy) = socks5_proxy(None).await;
+        let proxy = Proxy::parse(&format!("socks5://{proxy_addr}")).unwrap();
+        let target = ServerAddress::parse(&format!("localhost:{}", addr.port())).unwrap();
+        match proxy.connect(&target).await {
+            Err(ConnectError::Proxy { reason, .. }) => assert!(reason.contains("refused")),
+            other => panic!("expected a proxy error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_connects_through_http_connect() {
+        let server = MockServer::start().await.unwrap();
+        let (proxy_addr, _proxy) = http_proxy(Some("Basic YWxpY2U6c2VjcmV0")).await;
+        let proxy = Proxy::parse(&format!("http://alice:secret@{proxy_addr}")).unwrap();
+
+        let mut client = connect_through(&server, &proxy).await.unwrap();
+        // the greeting the server sends right away isn't lost with the proxy's response
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Status { .. }
+        ));
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::ChangeTopic { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn silent_proxies_time_out() {
+        // accepts the connection but never answers the handshake
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let proxy = Proxy::parse(&format!("socks5://{proxy_addr}")).unwrap();
+        let target = ServerAddress::parse("localhost:10000").unwrap();
+
+        match proxy
+            .connect_within(&target, Duration::from_millis(100))
+            .await
+        {
+            Err(ConnectError::Proxy { reason, .. }) => assert!(reason.contains("in time")),
+            other => panic!("expected a proxy error, got {other:?}"),
+        }
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn http_proxy_refusals_are_reported() {
+        let server = MockServer::start().await.unwrap();
+        let (proxy_addr, _proxy) = http_proxy(Some("Basic YWxpY2U6c2VjcmV0")).await;
+        let proxy = Proxy::parse(&format!("http://{proxy_addr}")).unwrap();
+
+        let err = connect_through(&server, &proxy).await.err().unwrap();
+        let err = ConnectError::from_io_error(&err).unwrap();
+        assert!(err.to_string().contains("407"), "{err}");
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+/// How a [`ChatSession`](crate::chat_session::ChatSession) retries a lost connection.
+///
+/// The delay grows from `initial_delay` by `multiplier` after every failed attempt, up to
+/// `max_delay`. `jitter` spreads each delay randomly by up to that fraction in either direction
+/// so that clients dropped at the same time don't all come back at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    /// Between 0.0 and 1.0.
+    pub jitter: f64,
+    /// Consecutive failed attempts before giving up, `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(60),
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Retries forever with the same delay.
+    pub fn fixed(delay: Duration) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: delay,
+            multiplier: 1.0,
+            max_delay: delay,
+            jitter: 0.0,
+            max_attempts: None,
+        }
+    }
+
+    /// Delay before retry number `attempt` (starting from 0) without jitter.
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .max(1.0)
+            .powi(attempt.min(i32::MAX as u32) as i32);
+        let delay = self.initial_delay.as_secs_f64() * factor;
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+
+    /// Delay before retry number `attempt`, or `None` when the attempts have run out.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| attempt >= max) {
+            return None;
+        }
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let spread = 1.0 + jitter * (fastrand::f64() * 2.0 - 1.0);
+        Some(self.base_delay(attempt).mul_f64(spread))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_grows_until_max() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(10),
+            jitter: 0.0,
+            max_attempts: None,
+        };
+        let delays: Vec<u64> = (0..6)
+            .map(|attempt| policy.delay(attempt).unwrap().as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(policy.delay(10_000), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let policy = ReconnectPolicy {
+            jitter: 0.5,
+            ..ReconnectPolicy::fixed(Duration::from_secs(4))
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(0).unwrap();
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(6));
+        }
+    }
+
+    #[test]
+    fn attempts_run_out() {
+        let policy = ReconnectPolicy {
+            max_attempts: Some(2),
+            ..ReconnectPolicy::default()
+        };
+        assert!(policy.delay(0).is_some());
+        assert!(policy.delay(1).is_some());
+        assert!(policy.delay(2).is_none());
+    }
+}
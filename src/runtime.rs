@@ -0,0 +1,25 @@
+//! Timers and background tasks, run by smol in native builds and by the browser's event loop in
+//! `wasm32` builds.
+
+use std::future::Future;
+use std::time::Duration;
+
+/// Waits for `duration` to pass.
+pub async fn sleep(duration: Duration) {
+    #[cfg(not(target_arch = "wasm32"))]
+    smol::Timer::after(duration).await;
+    #[cfg(target_arch = "wasm32")]
+    gloo_timers::future::sleep(duration).await;
+}
+
+/// Runs `task` in the background until it's done, whether or not anyone waits for it.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn_detached(task: impl Future<Output = ()> + Send + 'static) {
+    smol::spawn(task).detach();
+}
+
+/// Runs `task` in the background until it's done, whether or not anyone waits for it.
+#[cfg(target_arch = "wasm32")]
+pub fn spawn_detached(task: impl Future<Output = ()> + 'static) {
+    wasm_bindgen_futures::spawn_local(task);
+}
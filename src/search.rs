@@ -0,0 +1,546 @@
+//! Full-text search over chat messages.
+//!
+//! Queries are words to look for, all of which have to be in the message text or the sender's
+//! name, mixed with filters:
+//!
+//! - `from:<user>` only messages sent by `user`
+//! - `in:<channel>` or `in:@<user>` only messages of that channel or direct message conversation
+//! - `after:<YYYY-MM-DD>` and `before:<YYYY-MM-DD>` only messages sent after or before that day,
+//!   in local time
+//! - `has:reply` only messages that got replies, `is:reply` only replies
+//!
+//! Filter values with spaces go in double quotes, e.g. `in:"the lounge"`. A word matches any
+//! word that starts with it, case-insensitively.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::ops::Bound;
+
+use chrono::{Local, NaiveDate, TimeZone};
+use uuid::Uuid;
+
+use crate::history::Conversation;
+use crate::packet::ChatMessage;
+
+/// Splits text into lowercase words.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// A filter like `from:` without anything after it.
+    MissingValue(String),
+    /// A date that isn't `YYYY-MM-DD`.
+    InvalidDate(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::MissingValue(filter) => write!(f, "{filter} needs a value"),
+            QueryError::InvalidDate(date) => {
+                write!(f, "{date} isn't a date, write it as YYYY-MM-DD")
+            }
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// A parsed search, see the [module documentation](self) for the syntax.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    /// Lowercase words that all have to match.
+    pub words: Vec<String>,
+    pub from: Option<String>,
+    pub conversation: Option<Conversation>,
+    /// Unix time in milliseconds, inclusive.
+    pub sent_after: Option<i64>,
+    /// Unix time in milliseconds, exclusive.
+    pub sent_before: Option<i64>,
+    pub has_reply: bool,
+    pub is_reply: bool,
+}
+
+/// Splits a query at whitespace, keeping what's in double quotes together.
+fn tokens(query: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in query.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// The start of `date` in local time, as Unix time in milliseconds.
+pub(crate) fn start_of_day(date: NaiveDate) -> Option<i64> {
+    let midnight = date.and_hms_opt(0, 0, 0)?;
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .map(|time| time.timestamp_millis())
+}
+
+fn parse_day(value: &str) -> Result<NaiveDate, QueryError> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| QueryError::InvalidDate(value.to_string()))
+}
+
+impl SearchQuery {
+    pub fn parse(query: &str) -> Result<SearchQuery, QueryError> {
+        let mut search = SearchQuery::default();
+        for token in tokens(query) {
+            let Some((filter, value)) = token.split_once(':') else {
+                search.words.extend(words(&token));
+                continue;
+            };
+            let filter = filter.to_lowercase();
+            let known = ["from", "in", "after", "before", "has", "is"];
+            if known.contains(&filter.as_str()) && value.is_empty() {
+                return Err(QueryError::MissingValue(format!("{filter}:")));
+            }
+            match (filter.as_str(), value) {
+                ("from", user) => search.from = Some(user.to_string()),
+                ("in", conversation) => {
+                    search.conversation = Some(match conversation.strip_prefix('@') {
+                        Some(user) => Conversation::Direct(user.to_string()),
+                        None => Conversation::Channel(conversation.to_string()),
+                    })
+                }
+                ("after", day) => {
+                    let next_day = parse_day(day)?.succ_opt();
+                    search.sent_after = next_day.and_then(start_of_day);
+                }
+                ("before", day) => search.sent_before = start_of_day(parse_day(day)?),
+                ("has", "reply" | "replies") => search.has_reply = true,
+                ("is", "reply") => search.is_reply = true,
+                // anything else is just text, like a time of day
+                _ => search.words.extend(words(&token)),
+            }
+        }
+        Ok(search)
+    }
+
+    /// Whether the query has nothing in it, which matches every message.
+    pub fn is_empty(&self) -> bool {
+        *self == SearchQuery::default()
+    }
+}
+
+/// A message found by [`SearchIndex::search`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub conversation: Conversation,
+    pub message: ChatMessage,
+    /// Messages right before the hit in its conversation, oldest first.
+    pub before: Vec<ChatMessage>,
+    /// Messages right after the hit in its conversation.
+    pub after: Vec<ChatMessage>,
+}
+
+/// Where the messages a [`SearchIndex`] finds are kept.
+pub trait Conversations {
+    /// The messages of `conversation`, in order.
+    fn messages(&self, conversation: &Conversation) -> &[ChatMessage];
+
+    /// Where the message with `id` is in its conversation.
+    fn position(&self, id: &Uuid) -> Option<usize>;
+}
+
+/// What the index keeps of a message, the message itself stays with its conversation.
+#[derive(Debug)]
+struct Document {
+    conversation: Conversation,
+    id: Uuid,
+    sent: i64,
+    is_reply: bool,
+}
+
+/// An inverted index of messages, by the words in their text and sender's name.
+///
+/// Documents are numbered in the order they're added, so every posting list stays sorted and
+/// searching only merges the lists of the words asked for. Hits and their context are looked up
+/// by id in the [`Conversations`] the caller keeps.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    documents: Vec<Document>,
+    ids: HashSet<Uuid>,
+    words: BTreeMap<String, Vec<u32>>,
+    users: HashMap<String, Vec<u32>>,
+    conversations: HashMap<Conversation, Vec<u32>>,
+    /// Ids of the messages that have replies.
+    replied_to: HashSet<Uuid>,
+}
+
+/// The documents in both sorted lists.
+fn intersect(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (mut i, mut j) = (0, 0);
+    let mut out = vec![];
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    out
+}
+
+impl SearchIndex {
+    pub fn new() -> SearchIndex {
+        SearchIndex::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Adds a message of `conversation`. A message that's already in the index isn't added twice.
+    pub fn add(&mut self, conversation: Conversation, message: &ChatMessage) {
+        if !self.ids.insert(message.id) {
+            return;
+        }
+        let document = self.documents.len() as u32;
+        let mut message_words: Vec<String> = words(&message.message)
+            .chain(words(&message.user))
+            .collect();
+        message_words.sort();
+        message_words.dedup();
+        for word in message_words {
+            self.words.entry(word).or_default().push(document);
+        }
+        self.users
+            .entry(message.user.to_lowercase())
+            .or_default()
+            .push(document);
+        self.conversations
+            .entry(conversation.clone())
+            .or_default()
+            .push(document);
+        if let Some(parent) = message.inReplyTo {
+            self.replied_to.insert(parent);
+        }
+        self.documents.push(Document {
+            conversation,
+            id: message.id,
+            sent: message.sent,
+            is_reply: message.inReplyTo.is_some(),
+        });
+    }
+
+    /// The documents with a word starting with `prefix`, sorted.
+    fn starting_with(&self, prefix: &str) -> Vec<u32> {
+        let mut documents: Vec<u32> = self
+            .words
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(word, _)| word.starts_with(prefix))
+            .flat_map(|(_, documents)| documents.iter().copied())
+            .collect();
+        documents.sort_unstable();
+        documents.dedup();
+        documents
+    }
+
+    /// Up to `limit` messages matching `query`, newest first, each with `context` messages
+    /// around it from `conversations`.
+    pub fn search(
+        &self,
+        query: &SearchQuery,
+        limit: usize,
+        context: usize,
+        conversations: &impl Conversations,
+    ) -> Vec<SearchHit> {
+        let mut found: Vec<u32> = self
+            .candidates(query)
+            .into_iter()
+            .filter(|document| {
+                let document = &self.documents[*document as usize];
+                query.sent_after.is_none_or(|after| document.sent >= after)
+                    && query
+                        .sent_before
+                        .is_none_or(|before| document.sent < before)
+                    && (!query.has_reply || self.replied_to.contains(&document.id))
+                    && (!query.is_reply || document.is_reply)
+            })
+            .collect();
+        // imported messages can be older than those added before them, so it goes by when they
+        // were sent, and only the hits that are returned get sorted
+        let newest_first = |document: &u32| {
+            (
+                Reverse(self.documents[*document as usize].sent),
+                Reverse(*document),
+            )
+        };
+        if found.len() > limit {
+            found.select_nth_unstable_by_key(limit, newest_first);
+            found.truncate(limit);
+        }
+        found.sort_unstable_by_key(newest_first);
+        found
+            .into_iter()
+            .filter_map(|document| hit(&self.documents[document as usize], conversations, context))
+            .collect()
+    }
+
+    /// The documents with every word of `query` and from the user and conversation asked for,
+    /// sorted. These are all the documents a search looks at.
+    fn candidates(&self, query: &SearchQuery) -> Vec<u32> {
+        // the narrowest lists first so the intersections stay small
+        let mut lists: Vec<Vec<u32>> = query
+            .words
+            .iter()
+            .map(|word| self.starting_with(word))
+            .collect();
+        if let Some(user) = &query.from {
+            lists.push(
+                self.users
+                    .get(&user.to_lowercase())
+                    .cloned()
+                    .unwrap_or_default(),
+            );
+        }
+        if let Some(conversation) = &query.conversation {
+            lists.push(
+                self.conversations
+                    .get(conversation)
+                    .cloned()
+                    .unwrap_or_default(),
+            );
+        }
+        lists.sort_by_key(Vec::len);
+        match lists.split_first() {
+            Some((first, rest)) => rest
+                .iter()
+                .fold(first.clone(), |documents, list| intersect(&documents, list)),
+            None => (0..self.documents.len() as u32).collect(),
+        }
+    }
+}
+
+/// The hit for `document`, with context from its conversation. `None` if the message isn't
+/// there.
+fn hit(
+    document: &Document,
+    conversations: &impl Conversations,
+    context: usize,
+) -> Option<SearchHit> {
+    let messages = conversations.messages(&document.conversation);
+    let position = conversations
+        .position(&document.id)
+        .filter(|position| messages.get(*position).is_some_and(|m| m.id == document.id))?;
+    let start = position.saturating_sub(context);
+    let end = (position + 1 + context).min(messages.len());
+    Some(SearchHit {
+        conversation: document.conversation.clone(),
+        message: messages[position].clone(),
+        before: messages[start..position].to_vec(),
+        after: messages[position + 1..end].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+
+    use crate::packet::Packet;
+    use crate::packet_builder::PacketBuilder;
+
+    use super::*;
+
+    fn message(user: &str, text: &str) -> ChatMessage {
+        match PacketBuilder::new(user.into()).chat_message(text.into()) {
+            Packet::Chat(message) => message,
+            _ => unreachable!(),
+        }
+    }
+
+    fn sent_on(mut message: ChatMessage, day: &str) -> ChatMessage {
+        let noon = NaiveDate::parse_from_str(day, "%Y-%m-%d")
+            .unwrap()
+            .and_time(NaiveTime::from_hms_opt(12, 0, 0).unwrap());
+        message.sent = Local.from_local_datetime(&noon).unwrap().timestamp_millis();
+        message
+    }
+
+    fn texts(hits: &[SearchHit]) -> Vec<&str> {
+        hits.iter()
+            .map(|hit| hit.message.message.as_str())
+            .collect()
+    }
+
+    fn main() -> Conversation {
+        Conversation::Channel("main".into())
+    }
+
+    /// An index and the conversations it was built from.
+    #[derive(Default)]
+    struct Indexed {
+        index: SearchIndex,
+        conversations: HashMap<Conversation, Vec<ChatMessage>>,
+        positions: HashMap<Uuid, usize>,
+    }
+
+    impl Conversations for Indexed {
+        fn messages(&self, conversation: &Conversation) -> &[ChatMessage] {
+            self.conversations
+                .get(conversation)
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+        }
+
+        fn position(&self, id: &Uuid) -> Option<usize> {
+            self.positions.get(id).copied()
+        }
+    }
+
+    impl Indexed {
+        fn add(&mut self, conversation: Conversation, message: ChatMessage) {
+            self.index.add(conversation.clone(), &message);
+            let messages = self.conversations.entry(conversation).or_default();
+            self.positions.insert(message.id, messages.len());
+            messages.push(message);
+        }
+
+        fn search(&self, query: &str, limit: usize, context: usize) -> Vec<SearchHit> {
+            let query = SearchQuery::parse(query).unwrap();
+            self.index.search(&query, limit, context, self)
+        }
+    }
+
+    #[test]
+    fn it_parses_filters() {
+        let query =
+            SearchQuery::parse(r#"Cats from:alice in:"the lounge" has:reply is:reply dogs"#)
+                .unwrap();
+        assert_eq!(query.words, ["cats", "dogs"]);
+        assert_eq!(query.from.as_deref(), Some("alice"));
+        assert_eq!(
+            query.conversation,
+            Some(Conversation::Channel("the lounge".into()))
+        );
+        assert!(query.has_reply && query.is_reply);
+
+        let query = SearchQuery::parse("in:@bob 12:30").unwrap();
+        assert_eq!(query.conversation, Some(Conversation::Direct("bob".into())));
+        assert_eq!(query.words, ["12", "30"]);
+
+        assert_eq!(
+            SearchQuery::parse("after:yesterday"),
+            Err(QueryError::InvalidDate("yesterday".into()))
+        );
+        assert_eq!(
+            SearchQuery::parse("from:"),
+            Err(QueryError::MissingValue("from:".into()))
+        );
+        assert!(SearchQuery::parse("  ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn words_match_by_prefix() {
+        let mut index = Indexed::default();
+        index.add(main(), message("alice", "Cats are great"));
+        index.add(main(), message("bob", "so are dogs"));
+        index.add(main(), message("carol", "catalogue of dogs"));
+
+        let search = |query: &str| index.search(query, 10, 0);
+        assert_eq!(
+            texts(&search("cat")),
+            ["catalogue of dogs", "Cats are great"]
+        );
+        assert_eq!(texts(&search("cat dogs")), ["catalogue of dogs"]);
+        assert_eq!(texts(&search("bob")), ["so are dogs"]);
+        assert!(search("birds").is_empty());
+        assert_eq!(search("").len(), 3);
+    }
+
+    #[test]
+    fn it_filters() {
+        let mut index = Indexed::default();
+        let question = sent_on(message("alice", "anyone here?"), "2024-03-01");
+        let mut answer = sent_on(message("bob", "here"), "2024-03-02");
+        answer.inReplyTo = Some(question.id);
+        index.add(main(), question);
+        index.add(main(), answer);
+        index.add(
+            Conversation::Direct("bob".into()),
+            sent_on(message("alice", "psst, here"), "2024-03-03"),
+        );
+
+        let search = |query: &str| index.search(query, 10, 0);
+        assert_eq!(
+            texts(&search("here from:Alice")),
+            ["psst, here", "anyone here?"]
+        );
+        assert_eq!(texts(&search("in:@bob")), ["psst, here"]);
+        assert_eq!(texts(&search("here in:main")), ["here", "anyone here?"]);
+        assert_eq!(
+            texts(&search("after:2024-03-01 before:2024-03-03")),
+            ["here"]
+        );
+        assert_eq!(texts(&search("has:reply")), ["anyone here?"]);
+        assert_eq!(texts(&search("is:reply")), ["here"]);
+    }
+
+    #[test]
+    fn hits_come_with_context() {
+        let mut index = Indexed::default();
+        for text in ["one", "two", "three", "four"] {
+            index.add(main(), message("alice", text));
+        }
+        index.add(
+            Conversation::Channel("other".into()),
+            message("bob", "five"),
+        );
+        let duplicate = index.conversations[&main()][0].clone();
+        index.index.add(main(), &duplicate);
+        assert_eq!(index.index.len(), 5);
+
+        let hits = index.search("three", 10, 1);
+        assert_eq!(texts(&hits), ["three"]);
+        assert_eq!(hits[0].before[0].message, "two");
+        assert_eq!(hits[0].after[0].message, "four");
+
+        let hits = index.search("four", 10, 2);
+        assert_eq!(hits[0].before.len(), 2);
+        assert!(hits[0].after.is_empty());
+    }
+
+    #[test]
+    fn searches_only_look_at_matching_messages() {
+        let mut index = Indexed::default();
+        let template = message("alice", "");
+        for i in 0..10_000 {
+            let mut message = template.clone();
+            message.id = Uuid::new_v4();
+            message.message = format!("message number {i} about topic{}", i % 100);
+            index.add(main(), message);
+        }
+        let query = SearchQuery::parse("topic42 number").unwrap();
+        assert_eq!(index.index.candidates(&query).len(), 100);
+        assert_eq!(index.search("topic42 number", 50, 2).len(), 50);
+    }
+}
@@ -0,0 +1,519 @@
+//! Parsing, resolving and connecting to `host:port` server addresses.
+//!
+//! A host name can resolve to several IPv4 and IPv6 addresses. [`ServerAddress::connect`] tries
+//! them in the Happy Eyeballs way (RFC 8305): alternating between the families, starting the next
+//! attempt when the previous one fails or hasn't connected within [`CONNECTION_ATTEMPT_DELAY`],
+//! and keeping whichever connects first. An attempt that hasn't connected within
+//! [`CONNECTION_ATTEMPT_TIMEOUT`] counts as failed.
+
+use std::fmt;
+#[cfg(not(target_arch = "wasm32"))]
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+use smol::Timer;
+#[cfg(not(target_arch = "wasm32"))]
+use smol::net::TcpStream;
+
+/// The port O4 servers listen on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 10000;
+
+/// How long an attempt gets before the next address is tried alongside it.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long an attempt gets before it's given up on, rather than waiting for the OS to.
+pub const CONNECTION_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A host name or IP address with a port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerAddress {
+    /// A host name or IP address, IPv6 addresses without brackets.
+    pub host: String,
+    pub port: u16,
+}
+
+/// One address that couldn't be connected to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedAttempt {
+    pub addr: SocketAddr,
+    pub kind: io::ErrorKind,
+    pub message: String,
+}
+
+/// Why connecting to a server didn't work out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectError {
+    /// The address isn't a `host:port` we can connect to.
+    InvalidAddress { address: String, reason: String },
+    /// The host name couldn't be looked up, or has no addresses.
+    Resolve { host: String, reason: String },
+    /// Every address of the server was tried and none of them connected.
+    Unreachable {
+        server: String,
+        attempts: Vec<FailedAttempt>,
+    },
+    /// The proxy was reached but couldn't connect us to the server.
+    Proxy { proxy: String, reason: String },
+}
+
+impl fmt::Display for FailedAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            io::ErrorKind::ConnectionRefused => write!(f, "{} refused the connection", self.addr),
+            io::ErrorKind::TimedOut => write!(f, "{} timed out", self.addr),
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+                write!(f, "{} is unreachable", self.addr)
+            }
+            _ => write!(f, "{}: {}", self.addr, self.message),
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::InvalidAddress { address, reason } => {
+                write!(f, "\"{address}\" isn't a valid server address: {reason}.")
+            }
+            ConnectError::Resolve { host, reason } => {
+                write!(f, "Couldn't look up {host}: {reason}.")
+            }
+            ConnectError::Unreachable { server, attempts } => {
+                write!(f, "Couldn't connect to {server}")?;
+                if attempts.is_empty() {
+                    return write!(f, ".");
+                }
+                let attempts: Vec<String> = attempts.iter().map(|a| a.to_string()).collect();
+                write!(f, ": {}.", attempts.join(", "))
+            }
+            ConnectError::Proxy { proxy, reason } => {
+                write!(f, "The proxy {proxy} didn't connect us: {reason}.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+impl ConnectError {
+    /// Finds the connect error behind an I/O error.
+    pub fn from_io_error(err: &io::Error) -> Option<&ConnectError> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    fn invalid(address: &str, reason: &str) -> ConnectError {
+        ConnectError::InvalidAddress {
+            address: address.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl From<ConnectError> for io::Error {
+    fn from(err: ConnectError) -> io::Error {
+        let kind = match &err {
+            ConnectError::InvalidAddress { .. } => io::ErrorKind::InvalidInput,
+            ConnectError::Resolve { .. } => io::ErrorKind::NotFound,
+            ConnectError::Unreachable { attempts, .. } => attempts
+                .last()
+                .map_or(io::ErrorKind::NotConnected, |attempt| attempt.kind),
+            ConnectError::Proxy { .. } => io::ErrorKind::ConnectionRefused,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl ServerAddress {
+    /// Parses `host`, `host:port`, `[ipv6]:port` or a bare IPv6 address, with [`DEFAULT_PORT`]
+    /// when there's no port.
+    pub fn parse(input: &str) -> Result<ServerAddress, ConnectError> {
+        ServerAddress::parse_with_default_port(input, DEFAULT_PORT)
+    }
+
+    pub fn parse_with_default_port(
+        input: &str,
+        default_port: u16,
+    ) -> Result<ServerAddress, ConnectError> {
+        let address = input.trim();
+        if address.is_empty() {
+            return Err(ConnectError::invalid(input, "it is empty"));
+        }
+
+        let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+            let Some((host, rest)) = rest.split_once(']') else {
+                return Err(ConnectError::invalid(input, "the [ isn't closed"));
+            };
+            if host.parse::<std::net::Ipv6Addr>().is_err() {
+                return Err(ConnectError::invalid(
+                    input,
+                    "only IPv6 addresses go in brackets",
+                ));
+            }
+            match rest {
+                "" => (host, None),
+                _ => match rest.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(ConnectError::invalid(input, "expected :port after ]")),
+                },
+            }
+        } else if address.matches(':').count() > 1 {
+            // a bare IPv6 address, which can't have a port without brackets
+            if address.parse::<std::net::Ipv6Addr>().is_err() {
+                return Err(ConnectError::invalid(
+                    input,
+                    "put IPv6 addresses in brackets, like [::1]:10000",
+                ));
+            }
+            (address, None)
+        } else {
+            match address.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            }
+        };
+
+        let port = match port {
+            None => default_port,
+            Some(port) => match port.parse::<u16>() {
+                Ok(port) if port != 0 => port,
+                _ => {
+                    return Err(ConnectError::invalid(
+                        input,
+                        "the port has to be a number from 1 to 65535",
+                    ));
+                }
+            },
+        };
+        if host.is_empty() {
+            return Err(ConnectError::invalid(input, "the host is missing"));
+        }
+        let valid_name = host
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '.' || c == '_');
+        if host.parse::<IpAddr>().is_err() && !valid_name {
+            return Err(ConnectError::invalid(
+                input,
+                "host names can only contain letters, digits, dots, dashes and underscores",
+            ));
+        }
+
+        Ok(ServerAddress {
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// Checks an address as it's typed on the login screen. `tcp://` and `tls://` addresses
+    /// are parsed, other schemes are left for their transport to check.
+    pub fn check(input: &str) -> Result<(), ConnectError> {
+        let address = input.trim();
+        let address = address
+            .strip_prefix("tcp://")
+            .or_else(|| address.strip_prefix("tls://"))
+            .unwrap_or(address);
+        if address.contains("://") {
+            return Ok(());
+        }
+        ServerAddress::parse(address).map(|_| ())
+    }
+
+    /// Every address the host resolves to, IPv6 and IPv4 alternating, starting with the family
+    /// the resolver put first.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>, ConnectError> {
+        let resolved = smol::net::resolve((self.host.as_str(), self.port))
+            .await
+            .map_err(|err| ConnectError::Resolve {
+                host: self.host.clone(),
+                reason: err.to_string(),
+            })?;
+        if resolved.is_empty() {
+            return Err(ConnectError::Resolve {
+                host: self.host.clone(),
+                reason: String::from("it has no addresses"),
+            });
+        }
+        Ok(interleave_families(resolved))
+    }
+
+    /// Connects to whichever resolved address answers first, see the [module docs](self).
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn connect(&self) -> Result<TcpStream, ConnectError> {
+        let candidates = self.resolve().await?;
+        race(
+            &candidates,
+            CONNECTION_ATTEMPT_DELAY,
+            CONNECTION_ATTEMPT_TIMEOUT,
+            TcpStream::connect,
+        )
+        .await
+        .map_err(|attempts| ConnectError::Unreachable {
+            server: self.to_string(),
+            attempts,
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = addrs[0].is_ipv6();
+    let (mut first, mut second): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+    let mut out = Vec::with_capacity(first.len() + second.len());
+    first.reverse();
+    second.reverse();
+    while !first.is_empty() || !second.is_empty() {
+        out.extend(first.pop());
+        out.extend(second.pop());
+    }
+    out
+}
+
+/// Starts an attempt on each candidate in turn, `stagger` apart or as soon as the previous one
+/// fails, each failing with `TimedOut` if it takes longer than `timeout`. The first connection
+/// wins and the other attempts are dropped.
+#[cfg(not(target_arch = "wasm32"))]
+async fn race<S, F>(
+    candidates: &[SocketAddr],
+    stagger: Duration,
+    timeout: Duration,
+    connect: impl Fn(SocketAddr) -> F,
+) -> Result<S, Vec<FailedAttempt>>
+where
+    S: Send + 'static,
+    F: Future<Output = io::Result<S>> + Send + 'static,
+{
+    let (result_tx, result_rx) = smol::channel::unbounded();
+    // attempts still running are cancelled when these are dropped
+    let mut attempts = vec![];
+    let mut failures = vec![];
+    let mut waiting = candidates.iter();
+    loop {
+        if let Some(&addr) = waiting.next() {
+            let result_tx = result_tx.clone();
+            let attempt = smol::future::or(connect(addr), async move {
+                Timer::after(timeout).await;
+                Err(io::ErrorKind::TimedOut.into())
+            });
+            attempts.push(smol::spawn(async move {
+                let _ = result_tx.send((addr, attempt.await)).await;
+            }));
+        } else if failures.len() == attempts.len() {
+            return Err(failures);
+        }
+
+        let result = if !waiting.as_slice().is_empty() {
+            smol::future::or(async { result_rx.recv().await.ok() }, async {
+                Timer::after(stagger).await;
+                None
+            })
+            .await
+        } else {
+            result_rx.recv().await.ok()
+        };
+        match result {
+            // the latest attempt is taking a while, start the next one next to it
+            None => {}
+            Some((_, Ok(stream))) => return Ok(stream),
+            Some((addr, Err(err))) => failures.push(FailedAttempt {
+                addr,
+                kind: err.kind(),
+                message: err.to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::net::TcpListener;
+
+    use super::*;
+
+    fn parse(input: &str) -> Result<(String, u16), ConnectError> {
+        ServerAddress::parse(input).map(|address| (address.host, address.port))
+    }
+
+    #[test]
+    fn it_parses_hosts_and_ports() {
+        assert_eq!(parse("example.com").unwrap(), ("example.com".into(), 10000));
+        assert_eq!(
+            parse(" 127.0.0.1:4000 ").unwrap(),
+            ("127.0.0.1".into(), 4000)
+        );
+        assert_eq!(parse("[::1]:4000").unwrap(), ("::1".into(), 4000));
+        assert_eq!(parse("[::1]").unwrap(), ("::1".into(), 10000));
+        assert_eq!(parse("fe80::1").unwrap(), ("fe80::1".into(), 10000));
+        assert_eq!(
+            ServerAddress::parse("[::1]:4000").unwrap().to_string(),
+            "[::1]:4000"
+        );
+
+        for bad in [
+            "",
+            ":4000",
+            "example.com:",
+            "example.com:0",
+            "example.com:http",
+        ] {
+            assert!(
+                matches!(parse(bad), Err(ConnectError::InvalidAddress { .. })),
+                "{bad} should be invalid"
+            );
+        }
+        assert!(parse("my server").is_err());
+        assert!(parse("[example.com]:4000").is_err());
+        assert!(parse("fe80::1:4000:zz").is_err());
+    }
+
+    #[test]
+    fn check_leaves_other_schemes_alone() {
+        assert!(ServerAddress::check("tls://example.com").is_ok());
+        assert!(ServerAddress::check("tls://example.com:x").is_err());
+        assert!(ServerAddress::check("unix:///run/relay.sock").is_ok());
+    }
+
+    #[test]
+    fn families_alternate() {
+        let addrs: Vec<SocketAddr> = [
+            "[::1]:1",
+            "[::2]:1",
+            "127.0.0.1:1",
+            "[::3]:1",
+            "127.0.0.2:1",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        let ordered: Vec<String> = interleave_families(addrs)
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        assert_eq!(
+            ordered,
+            [
+                "[::1]:1",
+                "127.0.0.1:1",
+                "[::2]:1",
+                "127.0.0.2:1",
+                "[::3]:1"
+            ]
+        );
+    }
+
+    /// An address nothing listens on.
+    async fn refused_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_falls_back_to_the_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let candidates = [refused_addr().await, listener.local_addr().unwrap()];
+        // a long stagger shows that a failure starts the next attempt right away
+        let stream = race(
+            &candidates,
+            Duration::from_secs(30),
+            CONNECTION_ATTEMPT_TIMEOUT,
+            TcpStream::connect,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), candidates[1]);
+    }
+
+    #[tokio::test]
+    async fn attempts_that_hang_time_out() {
+        let candidates = [refused_addr().await, refused_addr().await];
+        // the first address never answers, the second connects right away
+        let connect = |addr: SocketAddr| {
+            let hangs = addr == candidates[0];
+            async move {
+                if hangs {
+                    smol::future::pending::<()>().await;
+                }
+                Ok(addr)
+            }
+        };
+        let connected = race(
+            &candidates,
+            Duration::from_secs(30),
+            Duration::from_millis(50),
+            connect,
+        )
+        .await
+        .unwrap();
+        assert_eq!(connected, candidates[1]);
+
+        let hang = |_| smol::future::pending::<io::Result<()>>();
+        let attempts = race(
+            &candidates,
+            Duration::from_secs(30),
+            Duration::from_millis(50),
+            hang,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(attempts.len(), 2);
+        assert!(
+            attempts
+                .iter()
+                .all(|attempt| attempt.kind == io::ErrorKind::TimedOut)
+        );
+    }
+
+    #[tokio::test]
+    async fn every_failure_is_reported() {
+        let candidates = [refused_addr().await, refused_addr().await];
+        let attempts = race(
+            &candidates,
+            CONNECTION_ATTEMPT_DELAY,
+            CONNECTION_ATTEMPT_TIMEOUT,
+            TcpStream::connect,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(attempts.len(), 2);
+        assert!(
+            attempts
+                .iter()
+                .all(|attempt| attempt.kind == io::ErrorKind::ConnectionRefused)
+        );
+
+        let err = ConnectError::Unreachable {
+            server: String::from("localhost:10000"),
+            attempts,
+        };
+        assert!(err.to_string().contains("refused the connection"));
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(matches!(
+            ConnectError::from_io_error(&err),
+            Some(ConnectError::Unreachable { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_hosts_fail_to_resolve() {
+        let address = ServerAddress::parse("no-such-host.invalid").unwrap();
+        assert!(matches!(
+            address.connect().await,
+            Err(ConnectError::Resolve { .. })
+        ));
+    }
+}
@@ -1,60 +1,200 @@
-use smol::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use smol::net::TcpStream;
+use async_lock::Mutex;
+use futures_lite::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::packet::Packet;
+use crate::proxy::Proxy;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::server_address::ServerAddress;
+use crate::tls::TlsOptions;
+use crate::transport::{SharedTransport, Transport};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{proxy, tls};
 
-pub struct TcpChatClient {
-    stream: TcpStream,
-    reader: BufReader<TcpStream>,
+struct LineReader {
+    lines: BufReader<SharedTransport>,
+    /// The line being read, kept here so that a cancelled `recv` doesn't lose half of it.
+    line: Vec<u8>,
 }
 
-impl Clone for TcpChatClient {
-    fn clone(&self) -> Self {
-        TcpChatClient {
-            stream: self.stream.clone(),
-            reader: BufReader::new(self.stream.clone()),
-        }
-    }
+/// A connection to an O4 server. Clones share the connection.
+#[derive(Clone)]
+pub struct TcpChatClient {
+    transport: SharedTransport,
+    reader: Arc<Mutex<LineReader>>,
+    writer: Arc<Mutex<SharedTransport>>,
 }
 
 impl TcpChatClient {
+    /// Connects to `addr`, `127.0.0.1:10000` by default. A missing port means
+    /// [`DEFAULT_PORT`](crate::server_address::DEFAULT_PORT), and connection failures come with a
+    /// [`ConnectError`](crate::server_address::ConnectError) inside. Addresses starting with
+    /// `tls://` are connected to over TLS with the default [`TlsOptions`], and on Unix
+    /// `unix://<path>` connects to a Unix domain socket. With the `web` feature, `ws://` and
+    /// `wss://` URLs open a WebSocket, the only kind of address `wasm32` builds connect to.
     pub async fn connect(addr: Option<&str>) -> io::Result<TcpChatClient> {
+        TcpChatClient::connect_with(addr, &TlsOptions::default()).await
+    }
+
+    pub async fn connect_with(addr: Option<&str>, tls: &TlsOptions) -> io::Result<TcpChatClient> {
+        TcpChatClient::connect_via(addr, tls, None).await
+    }
+
+    /// Like [`TcpChatClient::connect_with`], with TCP connections (TLS and WebSockets included)
+    /// going through `proxy` if there is one.
+    pub async fn connect_via(
+        addr: Option<&str>,
+        tls: &TlsOptions,
+        proxy: Option<&Proxy>,
+    ) -> io::Result<TcpChatClient> {
         let addr = addr.unwrap_or("127.0.0.1:10000");
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix://") {
+            let stream = smol::net::unix::UnixStream::connect(path)
+                .await
+                .inspect_err(|_| println!("Failed to connect to {}", addr))?;
+            return Ok(TcpChatClient::from_transport(stream));
+        }
+        #[cfg(all(feature = "web", not(target_arch = "wasm32")))]
+        if addr.starts_with("ws://") || addr.starts_with("wss://") {
+            let transport = crate::websocket::connect(addr, tls, proxy)
+                .await
+                .inspect_err(|_| println!("Failed to connect to {}", addr))?;
+            return Ok(TcpChatClient::from_transport(transport));
+        }
+        #[cfg(all(feature = "web", target_arch = "wasm32"))]
+        if addr.starts_with("ws://") || addr.starts_with("wss://") {
+            let transport = crate::browser_websocket::connect(addr)
+                .await
+                .inspect_err(|_| println!("Failed to connect to {}", addr))?;
+            return Ok(TcpChatClient::from_transport(transport));
+        }
+        TcpChatClient::connect_socket(addr, tls, proxy).await
+    }
+
+    /// Connects to a `host:port`, `tcp://` or `tls://` address.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn connect_socket(
+        addr: &str,
+        tls: &TlsOptions,
+        proxy: Option<&Proxy>,
+    ) -> io::Result<TcpChatClient> {
+        let (use_tls, host_port) = match addr.strip_prefix("tls://") {
+            Some(host_port) => (true, host_port),
+            None => (false, addr.strip_prefix("tcp://").unwrap_or(addr)),
+        };
 
-        match TcpStream::connect(&addr).await {
+        let address = ServerAddress::parse(host_port)?;
+        let socket = match proxy::connect(&address, proxy).await {
             Err(err) => {
-                println!("Failed to connect to {}", addr);
-                Err(err)
+                println!("Failed to connect to {}: {}", addr, err);
+                return Err(err.into());
             }
-            Ok(stream) => Ok(TcpChatClient {
-                reader: BufReader::new(stream.clone()),
-                stream,
-            }),
+            Ok(socket) => socket,
+        };
+        if use_tls {
+            Ok(TcpChatClient::from_transport(
+                tls::connect(socket, &address.to_string(), tls).await?,
+            ))
+        } else {
+            Ok(TcpChatClient::from_transport(socket))
         }
     }
 
+    /// Browsers don't let pages open sockets, only WebSockets.
+    #[cfg(target_arch = "wasm32")]
+    async fn connect_socket(
+        addr: &str,
+        _tls: &TlsOptions,
+        _proxy: Option<&Proxy>,
+    ) -> io::Result<TcpChatClient> {
+        Err(crate::server_address::ConnectError::InvalidAddress {
+            address: addr.to_string(),
+            reason: String::from("a browser can only connect to ws:// and wss:// addresses"),
+        }
+        .into())
+    }
+
+    /// Speaks the protocol over an already open connection, e.g. one end of
+    /// [`duplex`](crate::transport::duplex).
+    pub fn from_transport(transport: impl Transport) -> TcpChatClient {
+        let transport = SharedTransport::new(transport);
+        TcpChatClient {
+            reader: Arc::new(Mutex::new(LineReader {
+                lines: BufReader::new(transport.clone()),
+                line: Vec::new(),
+            })),
+            writer: Arc::new(Mutex::new(transport.clone())),
+            transport,
+        }
+    }
+
+    /// Turns TCP keepalive on with the OS probing after `idle` of silence, or off with `None`.
+    /// Does nothing on connections that aren't TCP.
+    pub fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        self.transport.set_keepalive(idle)
+    }
+
+    /// Closes the connection for every clone of this client.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.transport.shutdown()
+    }
+
+    /// Writes the packet and the newline after it.
+    ///
+    /// Cancel safe: once the future has started writing, the packet is written in full even if
+    /// it's dropped, so giving up on a send never leaves half a packet on the stream.
     pub async fn send(&self, packet: Packet) -> io::Result<usize> {
         let mut data = packet.to_bytes();
         data.push(b'\n');
 
         // println!("send(): {}", String::from_utf8(data.clone()).unwrap());
 
-        self.stream.clone().write(data.as_slice()).await
+        // locked here so that packets go out in the order they were sent
+        let mut writer = self.writer.lock_arc().await;
+        let (done_tx, done_rx) = async_channel::bounded(1);
+        crate::runtime::spawn_detached(async move {
+            let written = async {
+                writer.write_all(data.as_slice()).await?;
+                writer.flush().await?;
+                Ok(data.len())
+            }
+            .await;
+            let _ = done_tx.try_send(written);
+        });
+        done_rx
+            .recv()
+            .await
+            .unwrap_or_else(|_| Err(io::Error::other("the write was abandoned")))
     }
+    /// Reads the next packet from the server. Lines that can't be decoded into a packet are
+    /// logged and skipped.
+    ///
+    /// Cancel safe: dropping the future before it finishes keeps what was read for the next call.
     pub async fn recv(&mut self) -> io::Result<Packet> {
-        let mut str = String::new();
-        match self.reader.read_line(&mut str).await {
-            Err(err) => {
-                println!("TcpChatClient read: error reading line");
-                Err(err)
-            }
-            Ok(size) => {
-                if size == 0 {
-                    return Err(std::io::ErrorKind::ConnectionAborted.into());
+        let mut reader = self.reader.lock().await;
+        let LineReader { lines, line } = &mut *reader;
+        loop {
+            match lines.read_until(b'\n', line).await {
+                Err(err) => {
+                    println!("TcpChatClient read: error reading line");
+                    return Err(err);
+                }
+                Ok(size) => {
+                    if size == 0 {
+                        return Err(std::io::ErrorKind::ConnectionAborted.into());
+                    }
+                    let line = std::mem::take(line);
+                    // println!("recv(): {}", String::from_utf8_lossy(&line));
+                    match Packet::from_bytes(&line) {
+                        Ok(packet) => return Ok(packet),
+                        Err(err) => {
+                            println!("TcpChatClient read: skipping malformed packet: {err}");
+                        }
+                    }
                 }
-                // println!("recv(): {}", str.as_str());
-                Ok(Packet::from_bytes(str.as_bytes()))
             }
         }
     }
@@ -62,23 +202,115 @@ impl TcpChatClient {
 
 #[cfg(test)]
 mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    use smol::io::{AsyncRead, AsyncWrite};
+
+    use crate::mock_server::MockServer;
     use crate::packet_builder::PacketBuilder;
 
     use super::*;
 
+    /// Takes a few bytes per write, and no more than `budget` until it's raised.
+    struct Trickle {
+        written: Arc<std::sync::Mutex<Vec<u8>>>,
+        budget: Arc<std::sync::Mutex<(usize, Option<Waker>)>>,
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut budget = self.budget.lock().unwrap();
+            let len = buf.len().min(4).min(budget.0);
+            if len == 0 {
+                budget.1 = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            budget.0 -= len;
+            self.written.lock().unwrap().extend_from_slice(&buf[..len]);
+            Poll::Ready(Ok(len))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Transport for Trickle {
+        fn shutdown(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn a_dropped_send_still_writes_the_whole_packet() {
+        let written = Arc::new(std::sync::Mutex::new(vec![]));
+        let budget = Arc::new(std::sync::Mutex::new((4, None)));
+        let client = TcpChatClient::from_transport(Trickle {
+            written: written.clone(),
+            budget: budget.clone(),
+        });
+        let builder = PacketBuilder::new("test user".into());
+        let first = builder.chat_message("a message longer than one write".into());
+        let second = builder.list_channels();
+
+        // given up on after the first poll, like a probe that timed out
+        assert!(
+            smol::future::poll_once(client.send(first.clone()))
+                .await
+                .is_none()
+        );
+        let waker = {
+            let mut budget = budget.lock().unwrap();
+            budget.0 = usize::MAX;
+            budget.1.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        client.send(second.clone()).await.unwrap();
+
+        let expected = [
+            first.to_bytes(),
+            b"\n".to_vec(),
+            second.to_bytes(),
+            b"\n".to_vec(),
+        ];
+        assert_eq!(*written.lock().unwrap(), expected.concat());
+    }
+
     #[tokio::test]
     async fn it_connects() {
-        match TcpChatClient::connect(Some("127.0.0.1:10000")).await {
-            Err(err) => panic!("{err}"),
-            Ok(_client) => {
-                assert!(true);
-            }
+        let server = MockServer::start().await.unwrap();
+        if let Err(err) = TcpChatClient::connect(Some(&server.address())).await {
+            panic!("{err}");
         }
     }
     #[tokio::test]
     async fn it_can_list_channels() {
+        let server = MockServer::start_with_channels(&[("main", "topic"), ("other", "")])
+            .await
+            .unwrap();
         let packet_builder = PacketBuilder::new("test user".into());
-        match TcpChatClient::connect(Some("127.0.0.1:10000")).await {
+        match TcpChatClient::connect(Some(&server.address())).await {
             Err(err) => panic!("{err}"),
             Ok(mut client) => {
                 let _status_msg = client.recv().await.expect("failed with recv()");
@@ -92,12 +324,30 @@ mod tests {
 
                 let channels_msg = client.recv().await.expect("failed with recv()");
                 match channels_msg {
-                    Packet::ListChannels { channels } => {
-                        assert!(channels.unwrap().len() == 2);
+                    Packet::ListChannels { channels, .. } => {
+                        assert_eq!(channels.unwrap(), vec!["main 1", "other 0"]);
                     }
                     _ => panic!("didn't get channels message as expected"),
                 }
             }
         }
     }
+    #[tokio::test]
+    async fn it_skips_malformed_lines() {
+        let server = MockServer::start().await.unwrap();
+        let mut client = TcpChatClient::connect(Some(&server.address()))
+            .await
+            .unwrap();
+        client.recv().await.unwrap();
+        client.recv().await.unwrap();
+
+        server.send_raw("this isn't json");
+        server.send_raw(r#"{"type": 0}"#);
+        server.inject_error("still here", false);
+
+        match client.recv().await.unwrap() {
+            Packet::Error { error, .. } => assert_eq!(error, "still here"),
+            _ => panic!("should be an error packet"),
+        }
+    }
 }
@@ -0,0 +1,428 @@
+//! TLS for `tls://` server addresses.
+//!
+//! Certificates are checked against the built-in web roots and an optional CA bundle. A server
+//! with a certificate that can't be verified that way, e.g. a self-signed one, can be trusted on
+//! first use: the connection fails with an [`UntrustedCertificate`] error, and once its
+//! fingerprint is added to the [`KnownHosts`] that certificate is accepted for that server.
+//!
+//! Browsers check `wss://` servers themselves, so `wasm32` builds only have the types.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::{Arc, Mutex};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+use ring::digest::{SHA256, digest};
+#[cfg(not(target_arch = "wasm32"))]
+use smol::net::{Shutdown, TcpStream};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_rustls::TlsConnector;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_rustls::rustls::crypto::ring as ring_provider;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_rustls::rustls::{self, ClientConfig, DigitallySignedStruct, RootCertStore};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::Transport;
+
+/// How `tls://` servers are verified.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsOptions {
+    /// PEM file with CA certificates to trust besides the built-in roots.
+    pub ca_file: Option<PathBuf>,
+    /// Where certificates trusted on first use are kept, `None` turns trusting on first use off.
+    pub known_hosts: Option<PathBuf>,
+}
+
+/// The server's certificate couldn't be verified and hasn't been trusted for it either.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UntrustedCertificate {
+    /// `host:port` of the server.
+    pub server: String,
+    /// SHA-256 of the certificate, see [`fingerprint`].
+    pub fingerprint: String,
+    /// The fingerprint trusted before, when the server's certificate has changed since.
+    pub previous: Option<String>,
+}
+
+impl fmt::Display for UntrustedCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.previous {
+            Some(_) => write!(
+                f,
+                "the certificate of {} has changed, it is now {}",
+                self.server, self.fingerprint
+            ),
+            None => write!(
+                f,
+                "the certificate of {} isn't trusted, its fingerprint is {}",
+                self.server, self.fingerprint
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UntrustedCertificate {}
+
+impl UntrustedCertificate {
+    /// Finds an untrusted certificate behind a connect error.
+    pub fn from_io_error(err: &io::Error) -> Option<&UntrustedCertificate> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+/// SHA-256 of a DER certificate as colon separated hex, like browsers show it.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn fingerprint(certificate: &[u8]) -> String {
+    digest(&SHA256, certificate)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// Fingerprints of certificates trusted on first use, per `host:port`, saved as JSON.
+#[derive(Debug, Default)]
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: BTreeMap<String, String>,
+}
+
+impl KnownHosts {
+    /// The known hosts file under [`crate::data_dir`].
+    pub fn default_path() -> PathBuf {
+        crate::data_dir().join("known_hosts.json")
+    }
+
+    /// Loads the file at `path`, a missing file means no trusted hosts yet.
+    pub fn load(path: impl Into<PathBuf>) -> io::Result<KnownHosts> {
+        let path = path.into();
+        let hosts = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(KnownHosts { path, hosts })
+    }
+
+    pub fn fingerprint(&self, server: &str) -> Option<&str> {
+        self.hosts.get(server).map(String::as_str)
+    }
+
+    /// Trusts the certificate with `fingerprint` for `server` from now on, replacing any earlier
+    /// one.
+    pub fn trust(&mut self, server: &str, fingerprint: &str) -> io::Result<()> {
+        self.hosts
+            .insert(server.to_string(), fingerprint.to_string());
+        crate::write_atomically(&self.path, |file| {
+            Ok(serde_json::to_writer_pretty(file, &self.hosts)?)
+        })
+    }
+}
+
+/// Accepts what the web roots verify, plus the one certificate trusted for the server.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+struct Verifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    server: String,
+    trusted: Option<String>,
+    trust_on_first_use: bool,
+    /// Where a rejected certificate is left for [`connect`] to report.
+    rejected: Arc<Mutex<Option<UntrustedCertificate>>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = fingerprint(end_entity);
+        if self.trusted.as_ref() == Some(&fingerprint) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let result = self.webpki.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        );
+        if result.is_err() && self.trust_on_first_use {
+            *self.rejected.lock().unwrap() = Some(UntrustedCertificate {
+                server: self.server.clone(),
+                fingerprint,
+                previous: self.trusted.clone(),
+            });
+        }
+        result
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn invalid_input(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+/// A TLS connection to the server, over TCP.
+#[cfg(not(target_arch = "wasm32"))]
+pub type TlsStream = Compat<tokio_rustls::client::TlsStream<Compat<TcpStream>>>;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Transport for TlsStream {
+    fn shutdown(&self) -> io::Result<()> {
+        socket(self).shutdown(Shutdown::Both)
+    }
+
+    fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        Transport::set_keepalive(socket(self), idle)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn socket(stream: &TlsStream) -> &TcpStream {
+    stream.get_ref().get_ref().0.get_ref()
+}
+
+/// Runs the TLS handshake over `stream` with the server at `server` (`host:port`).
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn connect(
+    stream: TcpStream,
+    server: &str,
+    options: &TlsOptions,
+) -> io::Result<TlsStream> {
+    let host = server
+        .rsplit_once(':')
+        .map_or(server, |(host, _port)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let server_name = ServerName::try_from(host.to_string()).map_err(invalid_input)?;
+
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(ca_file) = &options.ca_file {
+        for certificate in CertificateDer::pem_file_iter(ca_file).map_err(invalid_input)? {
+            roots
+                .add(certificate.map_err(invalid_input)?)
+                .map_err(invalid_input)?;
+        }
+    }
+
+    let trusted = match &options.known_hosts {
+        Some(path) => KnownHosts::load(path)?
+            .fingerprint(server)
+            .map(str::to_string),
+        None => None,
+    };
+    let provider = Arc::new(ring_provider::default_provider());
+    let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(invalid_input)?;
+    let rejected = Arc::new(Mutex::new(None));
+    let verifier = Verifier {
+        webpki,
+        server: server.to_string(),
+        trusted,
+        trust_on_first_use: options.known_hosts.is_some(),
+        rejected: rejected.clone(),
+    };
+
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_input)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+
+    match connector.connect(server_name, stream.compat()).await {
+        Ok(tls) => Ok(tls.compat()),
+        Err(err) => match rejected.lock().unwrap().take() {
+            Some(untrusted) => Err(io::Error::new(io::ErrorKind::InvalidData, untrusted)),
+            None => Err(err),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::Task;
+    use smol::io::AsyncWriteExt;
+    use smol::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use uuid::Uuid;
+
+    use crate::tcp_chat_client::TcpChatClient;
+
+    use super::*;
+
+    /// A TLS server with a new self-signed certificate for `localhost`, greeting every client
+    /// with a status packet.
+    async fn tls_server() -> (String, rcgen::Certificate, Task<()>) {
+        let rcgen::CertifiedKey { cert, signing_key } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(signing_key.serialize_der()));
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(ring_provider::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.der().clone()], key)
+                .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let task = smol::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let Ok(tls) = acceptor.accept(stream.compat()).await else {
+                    continue;
+                };
+                let mut tls = tls.compat();
+                let _ = tls
+                    .write_all(b"{\"type\": 0, \"status\": \"hello\"}\n")
+                    .await;
+                let _ = tls.flush().await;
+                // keep the connection open until the client has read the greeting
+                smol::Timer::after(std::time::Duration::from_secs(1)).await;
+            }
+        });
+        (addr, cert, task)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("neighbor_chat_{name}_{}", Uuid::new_v4()))
+    }
+
+    async fn greeting(mut client: TcpChatClient) -> String {
+        match client.recv().await.unwrap() {
+            crate::packet::Packet::Status { status, .. } => status,
+            other => panic!("expected a status, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_trusts_a_ca_file() {
+        let (addr, cert, _server) = tls_server().await;
+        let ca_file = temp_path("ca.pem");
+        fs::write(&ca_file, cert.pem()).unwrap();
+
+        let options = TlsOptions {
+            ca_file: Some(ca_file.clone()),
+            known_hosts: None,
+        };
+        let client = TcpChatClient::connect_with(Some(&format!("tls://{addr}")), &options)
+            .await
+            .unwrap();
+        assert_eq!(greeting(client).await, "hello");
+        fs::remove_file(ca_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn self_signed_needs_trust() {
+        let (addr, _cert, _server) = tls_server().await;
+        let err = TcpChatClient::connect(Some(&format!("tls://{addr}")))
+            .await
+            .err()
+            .unwrap();
+        assert!(UntrustedCertificate::from_io_error(&err).is_none());
+    }
+
+    #[tokio::test]
+    async fn trust_on_first_use() {
+        let (addr, cert, _server) = tls_server().await;
+        let known_hosts = temp_path("known_hosts.json");
+        let options = TlsOptions {
+            ca_file: None,
+            known_hosts: Some(known_hosts.clone()),
+        };
+        let tls_addr = format!("tls://{addr}");
+
+        let err = TcpChatClient::connect_with(Some(&tls_addr), &options)
+            .await
+            .err()
+            .unwrap();
+        let untrusted = UntrustedCertificate::from_io_error(&err).unwrap();
+        assert_eq!(untrusted.server, addr);
+        assert_eq!(untrusted.fingerprint, fingerprint(cert.der()));
+        assert_eq!(untrusted.previous, None);
+
+        let mut hosts = KnownHosts::load(&known_hosts).unwrap();
+        hosts.trust(&addr, &untrusted.fingerprint).unwrap();
+        let client = TcpChatClient::connect_with(Some(&tls_addr), &options)
+            .await
+            .unwrap();
+        assert_eq!(greeting(client).await, "hello");
+        fs::remove_file(known_hosts).unwrap();
+    }
+
+    #[tokio::test]
+    async fn changed_certificate_is_reported() {
+        let (addr, _cert, _server) = tls_server().await;
+        let known_hosts = temp_path("known_hosts.json");
+        KnownHosts::load(&known_hosts)
+            .unwrap()
+            .trust(&addr, "AA:BB")
+            .unwrap();
+        let options = TlsOptions {
+            ca_file: None,
+            known_hosts: Some(known_hosts.clone()),
+        };
+
+        let err = TcpChatClient::connect_with(Some(&format!("tls://{addr}")), &options)
+            .await
+            .err()
+            .unwrap();
+        let untrusted = UntrustedCertificate::from_io_error(&err).unwrap();
+        assert_eq!(untrusted.previous.as_deref(), Some("AA:BB"));
+        fs::remove_file(known_hosts).unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+//! Byte streams the newline delimited O4 framing runs over.
+//!
+//! [`TcpChatClient`](crate::tcp_chat_client::TcpChatClient) picks a transport from the address:
+//! plain TCP for `host:port` or `tcp://host:port`, TLS for `tls://host:port`, on Unix a Unix
+//! domain socket for `unix:///path/to/socket` and, with the `web` feature, a WebSocket for
+//! `ws://` and `wss://` URLs. `wasm32` builds only have the browser's WebSocket. [`duplex`]
+//! connects two ends in memory.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_channel::{Receiver, Sender, unbounded};
+use futures_lite::Stream;
+use futures_lite::io::{AsyncRead, AsyncWrite};
+#[cfg(not(target_arch = "wasm32"))]
+use smol::net::{Shutdown, TcpStream};
+#[cfg(not(target_arch = "wasm32"))]
+use socket2::{SockRef, TcpKeepalive};
+
+/// A connection to the server.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+    /// Closes both directions. A read waiting on the other end of a clone of the connection ends.
+    fn shutdown(&self) -> io::Result<()>;
+
+    /// Turns TCP keepalive on with probes after `idle` of silence, or off with `None`. Transports
+    /// that aren't TCP ignore it.
+    fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        let _ = idle;
+        Ok(())
+    }
+}
+
+impl Transport for Box<dyn Transport> {
+    fn shutdown(&self) -> io::Result<()> {
+        (**self).shutdown()
+    }
+
+    fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        (**self).set_keepalive(idle)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Transport for TcpStream {
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        let socket = SockRef::from(self);
+        let Some(idle) = idle else {
+            return socket.set_keepalive(false);
+        };
+        let keepalive = TcpKeepalive::new().with_time(idle);
+        #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+        let keepalive = keepalive.with_interval(idle / 3);
+        socket.set_tcp_keepalive(&keepalive)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for smol::net::unix::UnixStream {
+    fn shutdown(&self) -> io::Result<()> {
+        smol::net::unix::UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// A transport that can be read from and written to from different tasks at the same time.
+/// Clones share the connection.
+#[derive(Clone)]
+pub struct SharedTransport(Arc<Mutex<Box<dyn Transport>>>);
+
+impl SharedTransport {
+    pub fn new(transport: impl Transport) -> SharedTransport {
+        SharedTransport(Arc::new(Mutex::new(Box::new(transport))))
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.0.lock().unwrap().shutdown()
+    }
+
+    pub fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().set_keepalive(idle)
+    }
+}
+
+// the lock is only held for a single poll, so a pending read doesn't block writing
+impl AsyncRead for SharedTransport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut **self.0.lock().unwrap()).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SharedTransport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut **self.0.lock().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut **self.0.lock().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut **self.0.lock().unwrap()).poll_close(cx)
+    }
+}
+
+/// One end of an in-memory connection made with [`duplex`].
+pub struct MemoryTransport {
+    incoming: Pin<Box<Receiver<Vec<u8>>>>,
+    /// What's left of the last chunk received.
+    unread: Vec<u8>,
+    outgoing: Sender<Vec<u8>>,
+}
+
+/// Two connected in-memory transports. What's written to one can be read from the other.
+pub fn duplex() -> (MemoryTransport, MemoryTransport) {
+    let (a_tx, a_rx) = unbounded();
+    let (b_tx, b_rx) = unbounded();
+    (
+        MemoryTransport {
+            incoming: Box::pin(a_rx),
+            unread: vec![],
+            outgoing: b_tx,
+        },
+        MemoryTransport {
+            incoming: Box::pin(b_rx),
+            unread: vec![],
+            outgoing: a_tx,
+        },
+    )
+}
+
+impl AsyncRead for MemoryTransport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.unread.is_empty() {
+            match self.incoming.as_mut().poll_next(cx) {
+                Poll::Ready(Some(chunk)) => self.unread = chunk,
+                // the other end is gone
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = buf.len().min(self.unread.len());
+        buf[..len].copy_from_slice(&self.unread[..len]);
+        self.unread.drain(..len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for MemoryTransport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.outgoing.try_send(buf.to_vec()) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outgoing.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn shutdown(&self) -> io::Result<()> {
+        self.outgoing.close();
+        self.incoming.close();
+        Ok(())
+    }
+}
+
+/// Drops the connection when the last end is dropped, like a socket would.
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.outgoing.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    use crate::packet::Packet;
+    use crate::packet_builder::PacketBuilder;
+    use crate::tcp_chat_client::TcpChatClient;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn duplex_carries_lines_both_ways() {
+        let (mut a, b) = duplex();
+        let mut b = BufReader::new(b);
+
+        a.write_all(b"hello\nwor").await.unwrap();
+        a.write_all(b"ld\n").await.unwrap();
+        let mut line = String::new();
+        b.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "hello\n");
+        line.clear();
+        b.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "world\n");
+
+        b.get_mut().write_all(b"back\n").await.unwrap();
+        let mut a = BufReader::new(a);
+        line.clear();
+        a.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "back\n");
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_both_sides() {
+        let (a, b) = duplex();
+        let mut a = TcpChatClient::from_transport(a);
+        let mut b = BufReader::new(b);
+
+        a.shutdown().unwrap();
+        assert!(a.recv().await.is_err());
+        let mut line = String::new();
+        assert_eq!(b.read_line(&mut line).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn client_runs_over_memory() {
+        let (client_end, server_end) = duplex();
+        let client = TcpChatClient::from_transport(client_end);
+        let mut server = BufReader::new(server_end);
+
+        let builder = PacketBuilder::new("test user".into());
+        client.send(builder.list_channels()).await.unwrap();
+        let mut line = String::new();
+        server.read_line(&mut line).await.unwrap();
+        assert!(matches!(
+            Packet::from_bytes(line.as_bytes()).unwrap(),
+            Packet::ListChannels { channels: None, .. }
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn client_runs_over_unix_sockets() {
+        use smol::net::unix::UnixListener;
+        use uuid::Uuid;
+
+        use crate::mock_server::MockServer;
+
+        let path = std::env::temp_dir().join(format!("neighbor_chat_{}.sock", Uuid::new_v4()));
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = MockServer::start().await.unwrap();
+
+        let address = format!("unix://{}", path.display());
+        let (client, accepted) =
+            smol::future::zip(TcpChatClient::connect(Some(&address)), listener.accept()).await;
+        server.serve(accepted.unwrap().0);
+        let mut client = client.unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Status { .. }
+        ));
+        assert_eq!(server.client_count(), 1);
+
+        client.shutdown().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,300 @@
+//! The O4 protocol over WebSockets, for networks that only let HTTP(S) through.
+//!
+//! This is the native client's side of it, running over smol sockets like the other transports.
+//! `wasm32` builds use the browser's WebSocket instead, see `browser_websocket`.
+//!
+//! Every packet travels in its own text frame, without the trailing newline.
+//! [`WebSocketTransport`] turns frames into lines and back, so the rest of the client reads and
+//! writes lines as it does over TCP. [`bridge_client`] connects a WebSocket client to a plain O4
+//! server, see the `neighbor_chat_ws_bridge` binary.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use async_tungstenite::WebSocketStream;
+use async_tungstenite::tungstenite::{self, Message};
+use futures_util::{Sink, Stream};
+use smol::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use smol::net::TcpStream;
+
+use crate::proxy::{self, Proxy};
+use crate::server_address::ServerAddress;
+use crate::tls::{self, TlsOptions};
+use crate::transport::{SharedTransport, Transport};
+
+/// A WebSocket connection read and written as newline delimited packets.
+pub struct WebSocketTransport<S> {
+    ws: WebSocketStream<S>,
+    /// What's left of the last frame received, with its newline.
+    unread: Vec<u8>,
+    /// Written bytes that don't make up a whole line yet.
+    unsent: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketTransport<S> {
+    pub fn new(ws: WebSocketStream<S>) -> WebSocketTransport<S> {
+        WebSocketTransport {
+            ws,
+            unread: vec![],
+            unsent: vec![],
+        }
+    }
+
+    /// Runs the server side of the handshake over `stream`.
+    pub async fn accept(stream: S) -> io::Result<WebSocketTransport<S>> {
+        let ws = async_tungstenite::accept_async(stream)
+            .await
+            .map_err(to_io_error)?;
+        Ok(WebSocketTransport::new(ws))
+    }
+
+    /// Queues a frame for every complete line written so far.
+    fn poll_send_lines(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(end) = self.unsent.iter().position(|b| *b == b'\n') {
+            ready!(Pin::new(&mut self.ws).poll_ready(cx)).map_err(to_io_error)?;
+            let mut line: Vec<u8> = self.unsent.drain(..=end).collect();
+            line.pop();
+            let text = String::from_utf8(line)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            Pin::new(&mut self.ws)
+                .start_send(Message::text(text))
+                .map_err(to_io_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn to_io_error(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::ErrorKind::ConnectionAborted.into()
+        }
+        err => io::Error::other(err),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketTransport<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.unread.is_empty() {
+            let frame = match ready!(Pin::new(&mut self.ws).poll_next(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(err)) => return Poll::Ready(Err(to_io_error(err))),
+                None => return Poll::Ready(Ok(0)),
+            };
+            match frame {
+                Message::Text(text) => self.unread = text.as_bytes().to_vec(),
+                Message::Binary(data) => self.unread = data.to_vec(),
+                Message::Close(_) => return Poll::Ready(Ok(0)),
+                // pings are answered by tungstenite
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            }
+            // an empty frame isn't a packet, and would read as the end of the stream
+            if !self.unread.is_empty() {
+                self.unread.push(b'\n');
+            }
+        }
+        let len = buf.len().min(self.unread.len());
+        buf[..len].copy_from_slice(&self.unread[..len]);
+        self.unread.drain(..len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketTransport<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_send_lines(cx))?;
+        self.unsent.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_lines(cx))?;
+        Pin::new(&mut self.ws).poll_flush(cx).map_err(to_io_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_lines(cx))?;
+        Pin::new(&mut self.ws).poll_close(cx).map_err(to_io_error)
+    }
+}
+
+impl<S: Transport> Transport for WebSocketTransport<S> {
+    fn shutdown(&self) -> io::Result<()> {
+        self.ws.get_ref().shutdown()
+    }
+
+    fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        self.ws.get_ref().set_keepalive(idle)
+    }
+}
+
+/// Opens a WebSocket to a `ws://` or `wss://` URL. `wss://` connections are checked with `tls`
+/// like `tls://` ones.
+pub(crate) async fn connect(
+    url: &str,
+    tls: &TlsOptions,
+    proxy: Option<&Proxy>,
+) -> io::Result<Box<dyn Transport>> {
+    let (secure, rest) = match url.strip_prefix("wss://") {
+        Some(rest) => (true, rest),
+        None => (
+            false,
+            url.strip_prefix("ws://")
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a ws:// URL"))?,
+        ),
+    };
+    let authority = rest.split('/').next().unwrap_or(rest);
+    let address = ServerAddress::parse_with_default_port(authority, if secure { 443 } else { 80 })?;
+
+    let socket = proxy::connect(&address, proxy).await?;
+    if secure {
+        let stream = tls::connect(socket, &address.to_string(), tls).await?;
+        let (ws, _) = async_tungstenite::client_async(url, stream)
+            .await
+            .map_err(to_io_error)?;
+        Ok(Box::new(WebSocketTransport::new(ws)))
+    } else {
+        let (ws, _) = async_tungstenite::client_async(url, socket)
+            .await
+            .map_err(to_io_error)?;
+        Ok(Box::new(WebSocketTransport::new(ws)))
+    }
+}
+
+/// Accepts a WebSocket client on `stream` and relays its packets to and from the O4 server at
+/// `server`, until either side closes the connection.
+pub async fn bridge_client(stream: TcpStream, server: &str) -> io::Result<()> {
+    let client = SharedTransport::new(WebSocketTransport::accept(stream).await?);
+    let upstream = SharedTransport::new(TcpStream::connect(server).await?);
+
+    let result = smol::future::race(
+        relay_lines(client.clone(), upstream.clone()),
+        relay_lines(upstream.clone(), client.clone()),
+    )
+    .await;
+    let _ = client.shutdown();
+    let _ = upstream.shutdown();
+    result
+}
+
+/// Copies lines from `from` to `to`, flushing after each one so that no packet waits for the
+/// next.
+async fn relay_lines(from: SharedTransport, mut to: SharedTransport) -> io::Result<()> {
+    let mut lines = BufReader::new(from);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        if lines.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(());
+        }
+        to.write_all(&line).await?;
+        to.flush().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::Task;
+    use smol::net::TcpListener;
+
+    use crate::mock_server::MockServer;
+    use crate::packet::Packet;
+    use crate::packet_builder::PacketBuilder;
+    use crate::tcp_chat_client::TcpChatClient;
+
+    use super::*;
+
+    async fn start_bridge(server: &MockServer) -> (String, Task<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = server.address();
+        let task = smol::spawn(async move {
+            let mut clients = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                let server = server.clone();
+                clients.push(smol::spawn(async move {
+                    let _ = bridge_client(stream, &server).await;
+                }));
+            }
+        });
+        (format!("ws://{addr}/"), task)
+    }
+
+    #[tokio::test]
+    async fn it_talks_to_a_tcp_server_through_the_bridge() {
+        let server = MockServer::start_with_channels(&[("main", "hello"), ("dogs", "")])
+            .await
+            .unwrap();
+        let (url, _bridge) = start_bridge(&server).await;
+        let builder = PacketBuilder::new("test user".into());
+
+        let mut client = TcpChatClient::connect(Some(&url)).await.unwrap();
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Status { .. }
+        ));
+        match client.recv().await.unwrap() {
+            Packet::ChangeTopic { topic, .. } => assert_eq!(topic, "hello"),
+            _ => panic!("should be a topic packet"),
+        }
+
+        client.send(builder.list_channels()).await.unwrap();
+        match client.recv().await.unwrap() {
+            Packet::ListChannels { channels, .. } => {
+                assert_eq!(channels.unwrap(), vec!["dogs 0", "main 1"]);
+            }
+            _ => panic!("should be a channel list"),
+        }
+
+        server.disconnect_all();
+        assert!(client.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn every_packet_is_a_text_frame() {
+        use futures_util::StreamExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = smol::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = async_tungstenite::accept_async(stream).await.unwrap();
+            let frame = ws.next().await.unwrap().unwrap();
+            ws.send(Message::text(r#"{"type": 0, "status": "hi"}"#))
+                .await
+                .unwrap();
+            frame
+        });
+
+        let mut client = TcpChatClient::connect(Some(&url)).await.unwrap();
+        client
+            .send(PacketBuilder::new("test user".into()).list_channels())
+            .await
+            .unwrap();
+        match server.await {
+            Message::Text(text) => {
+                assert!(!text.ends_with('\n'));
+                assert!(matches!(
+                    Packet::from_bytes(text.as_bytes()).unwrap(),
+                    Packet::ListChannels { .. }
+                ));
+            }
+            other => panic!("expected a text frame, got {other:?}"),
+        }
+        assert!(matches!(
+            client.recv().await.unwrap(),
+            Packet::Status { .. }
+        ));
+    }
+}